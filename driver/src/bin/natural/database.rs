@@ -0,0 +1,167 @@
+//! The postgres database questions are asked about, read for its schema and to run answers.
+
+use std::fmt;
+use std::path::Path;
+
+use eyre::Result;
+use postgres::{Client, NoTls, SimpleQueryMessage};
+use serde::Serialize;
+
+/// `CREATE TABLE` statement of every table and view outside the system schemas
+const SCHEMA_QUERY: &str = "
+    SELECT format(
+        'CREATE TABLE %s (%s);',
+        c.oid::regclass,
+        string_agg(
+            format(
+                '%I %s%s',
+                a.attname,
+                format_type(a.atttypid, a.atttypmod),
+                CASE WHEN a.attnotnull THEN ' NOT NULL' ELSE '' END
+            ),
+            ', ' ORDER BY a.attnum
+        )
+    )
+    FROM pg_class c
+    JOIN pg_namespace n ON n.oid = c.relnamespace
+    JOIN pg_attribute a ON a.attrelid = c.oid AND a.attnum > 0 AND NOT a.attisdropped
+    WHERE c.relkind IN ('r', 'p', 'v', 'm')
+      AND n.nspname NOT IN ('pg_catalog', 'information_schema')
+      AND n.nspname NOT LIKE 'pg_toast%'
+    GROUP BY c.oid
+    ORDER BY c.oid::regclass::text";
+
+pub struct Database {
+    client: Client,
+}
+
+/// Result of a statement, with every value as text
+#[derive(Debug, Default, Serialize)]
+pub struct Rows {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Option<String>>>,
+}
+
+impl Database {
+    /// Connect to a database given by url, e.g. `postgres://localhost/shop`
+    pub fn connect(url: &str) -> Result<Self> {
+        Ok(Self {
+            client: Client::connect(url, NoTls)?,
+        })
+    }
+
+    /// The tables of the database, as `CREATE TABLE` statements
+    pub fn schema(&mut self) -> Result<String> {
+        let statements = self
+            .client
+            .query(SCHEMA_QUERY, &[])?
+            .iter()
+            .map(|row| row.get::<_, String>(0))
+            .collect::<Vec<_>>();
+
+        Ok(statements.join("\n"))
+    }
+
+    /// Run sql in a read only transaction, generated sql must not change the database
+    pub fn execute(&mut self, sql: &str) -> Result<Rows> {
+        let mut transaction = self.client.build_transaction().read_only(true).start()?;
+        let mut rows = Rows::default();
+
+        for message in transaction.simple_query(sql)? {
+            match message {
+                SimpleQueryMessage::RowDescription(columns) => {
+                    rows.columns = columns.iter().map(|c| c.name().to_string()).collect();
+                }
+                SimpleQueryMessage::Row(row) => {
+                    rows.rows.push(
+                        (0..row.len())
+                            .map(|i| row.get(i).map(str::to_string))
+                            .collect(),
+                    );
+                }
+                _ => {}
+            }
+        }
+
+        transaction.commit()?;
+
+        Ok(rows)
+    }
+
+    /// Record a model in `natural.models`, the database must run on the host of the file
+    pub fn register_model(&mut self, name: &str, path: &Path, sha256: Option<&str>) -> Result<()> {
+        self.client.execute(
+            "SELECT natural.register_model($1, $2, sha256 => $3)",
+            &[&name, &path.to_string_lossy().as_ref(), &sha256],
+        )?;
+
+        Ok(())
+    }
+}
+
+impl Rows {
+    /// Aligned table like psql prints it, followed by the row count
+    pub fn table(&self) -> String {
+        let mut widths = self
+            .columns
+            .iter()
+            .map(|c| c.chars().count())
+            .collect::<Vec<_>>();
+
+        for row in &self.rows {
+            for (width, value) in widths.iter_mut().zip(row) {
+                *width = (*width).max(value.as_deref().unwrap_or("").chars().count());
+            }
+        }
+
+        let line = |values: Vec<&str>| {
+            values
+                .iter()
+                .zip(&widths)
+                .map(|(value, width)| format!(" {value:<width$} "))
+                .collect::<Vec<_>>()
+                .join("|")
+                .trim_end()
+                .to_string()
+        };
+
+        let mut table = vec![
+            line(self.columns.iter().map(String::as_str).collect()),
+            widths
+                .iter()
+                .map(|width| "-".repeat(width + 2))
+                .collect::<Vec<_>>()
+                .join("+"),
+        ];
+
+        for row in &self.rows {
+            table.push(line(
+                row.iter().map(|v| v.as_deref().unwrap_or("")).collect(),
+            ));
+        }
+
+        table.push(match self.rows.len() {
+            1 => "(1 row)".to_string(),
+            n => format!("({n} rows)"),
+        });
+
+        table.join("\n")
+    }
+}
+
+impl fmt::Display for Rows {
+    /// Tab separated values, headed by the column names
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.columns.join("\t"))?;
+
+        for row in &self.rows {
+            let values = row
+                .iter()
+                .map(|v| v.as_deref().unwrap_or(""))
+                .collect::<Vec<_>>();
+            writeln!(f, "{}", values.join("\t"))?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,574 @@
+//! Command line interface of the driver, generating sql without postgres or the extension.
+//!
+//! ```sh
+//! natural ask --model model.gguf --schema schema.sql "Which users are named henry?"
+//! natural ask --model model.gguf --schema-from postgres://localhost/shop --execute "..."
+//! natural repl --model model.gguf --schema-from postgres://localhost/shop
+//! natural repl --daemon /run/natural/daemon.sock --schema-from postgres://localhost/shop
+//! natural install TheBloke/Mistral-7B-Instruct-v0.2-GGUF mistral-7b-instruct-v0.2.Q4_K_M.gguf
+//! ```
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::error::ErrorKind;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use eyre::{bail, Context, Result};
+use llama_cpp_2::context::params::LlamaContextParams;
+use llama_cpp_2::llama_backend::LlamaBackend;
+use llama_cpp_2::model::params::LlamaModelParams;
+use llama_cpp_2::model::LlamaModel;
+use natural_driver::chat::ChatFormat;
+use natural_driver::dialect::SqlDialect;
+use natural_driver::error::NaturalError;
+use natural_driver::generator::{Generation, Outcome, Sampling, SqlGenerator};
+use natural_driver::hub;
+use natural_driver::prompt::{Example, Prompt, PromptContext, PromptTemplate, Turn};
+use natural_driver::protocol::Client;
+use serde::Serialize;
+use signal_hook::consts::SIGINT;
+
+use crate::database::{Database, Rows};
+
+mod database;
+mod repl;
+
+/// Exit codes per kind of failure. Invalid arguments exit with 2, like every clap program.
+mod exit {
+    pub const FAILURE: u8 = 1;
+    /// The model could not be loaded, or the daemon serving it not reached
+    pub const MODEL: u8 = 3;
+    /// The prompt does not fit the context
+    pub const CONTEXT: u8 = 4;
+    /// The model answered without valid sql
+    pub const OUTPUT: u8 = 5;
+    pub const POLICY: u8 = 6;
+    pub const TIMEOUT: u8 = 7;
+    /// The database could not be read or the sql failed to run
+    pub const DATABASE: u8 = 8;
+    /// The model asked a clarifying question instead of answering
+    pub const CLARIFICATION: u8 = 9;
+    /// Generation was interrupted by Ctrl-C, like a shell reports SIGINT
+    pub const INTERRUPTED: u8 = 130;
+}
+
+#[derive(Parser)]
+#[command(
+    name = "natural",
+    version,
+    about = "Generate sql from questions in natural language"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Answer a single question
+    Ask {
+        question: String,
+        #[command(flatten)]
+        options: Options,
+        #[arg(long, value_enum, default_value_t = Format::Sql)]
+        format: Format,
+        /// Run the sql against the --schema-from database and print its result
+        #[arg(long)]
+        execute: bool,
+    },
+    /// Answer questions interactively as a conversation, running the sql against the
+    /// --schema-from database once confirmed
+    Repl {
+        #[command(flatten)]
+        options: Options,
+    },
+    /// Install a model of the Hugging Face hub from a local hub cache or mirror, without
+    /// network access
+    Install {
+        /// Repository of the model, e.g. TheBloke/Mistral-7B-Instruct-v0.2-GGUF
+        repo: String,
+        /// GGUF file in the repository
+        file: String,
+        #[command(flatten)]
+        options: InstallOptions,
+    },
+}
+
+#[derive(Args)]
+struct InstallOptions {
+    /// Hub cache or mirror laid out as <repo>/<file> to look up the model in, may be given
+    /// several times. Defaults to the hub cache of the environment.
+    #[arg(long = "source")]
+    sources: Vec<PathBuf>,
+    /// Directory to install the model in, defaults to $PGDATA/natural/models
+    #[arg(long)]
+    models_dir: Option<PathBuf>,
+    /// Record the model in natural.models of this database, e.g. postgres://localhost/shop
+    #[arg(long)]
+    register: Option<String>,
+    /// Name to register the model under, defaults to the file name without extension
+    #[arg(long, requires = "register")]
+    name: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    /// Only the sql, or the clarifying question of the model
+    Sql,
+    /// Everything known about the generation as a json object
+    Json,
+}
+
+#[derive(Args)]
+struct Options {
+    /// GGUF model file
+    #[arg(long, env = "NATURAL_MODEL", required_unless_present = "daemon")]
+    model: Option<PathBuf>,
+    /// Generate on the model served by a daemon listening on this socket instead of loading
+    /// one. The sampling and context options of the daemon apply.
+    #[arg(long, conflicts_with = "model")]
+    daemon: Option<PathBuf>,
+    /// File holding the schema as `CREATE TABLE` statements, e.g. from `pg_dump --schema-only`
+    #[arg(
+        long,
+        required_unless_present = "schema_from",
+        conflicts_with = "schema_from"
+    )]
+    schema: Option<PathBuf>,
+    /// Read the schema from a postgres database, e.g. postgres://localhost/shop
+    #[arg(long)]
+    schema_from: Option<String>,
+    #[arg(long, default_value_t = SqlDialect::Postgres)]
+    dialect: SqlDialect,
+    #[arg(long, default_value_t = ChatFormat::Auto)]
+    chat_format: ChatFormat,
+    /// File replacing the built-in system template
+    #[arg(long)]
+    system: Option<PathBuf>,
+    /// File replacing the built-in user template
+    #[arg(long)]
+    user: Option<PathBuf>,
+    /// File of few-shot examples, one json object with `question` and `sql` per line. The
+    /// repl appends to it with :save-example.
+    #[arg(long)]
+    examples: Option<PathBuf>,
+    /// Print the rendered prompt to stderr
+    #[arg(long)]
+    show_prompt: bool,
+    #[command(flatten)]
+    sampling: SamplingOptions,
+    /// Context size in tokens
+    #[arg(long, default_value_t = 4096)]
+    context_size: u32,
+    #[arg(long, default_value_t = 4)]
+    threads: i32,
+    /// Layers offloaded to the gpu
+    #[arg(long, default_value_t = 0)]
+    gpu_layers: u32,
+    /// Give up generating after this many seconds
+    #[arg(long)]
+    timeout: Option<u64>,
+}
+
+#[derive(Args)]
+struct SamplingOptions {
+    /// Randomness of sampling, 0 always picks the most likely token
+    #[arg(long, default_value_t = Sampling::default().temperature)]
+    temperature: f32,
+    #[arg(long, default_value_t = Sampling::default().top_k)]
+    top_k: i32,
+    #[arg(long, default_value_t = Sampling::default().top_p)]
+    top_p: f32,
+    #[arg(long, default_value_t = Sampling::default().seed)]
+    seed: u32,
+    #[arg(long, default_value_t = Sampling::default().max_tokens)]
+    max_tokens: usize,
+}
+
+impl From<&SamplingOptions> for Sampling {
+    fn from(options: &SamplingOptions) -> Self {
+        Self {
+            temperature: options.temperature,
+            top_k: options.top_k,
+            top_p: options.top_p,
+            seed: options.seed,
+            max_tokens: options.max_tokens,
+        }
+    }
+}
+
+/// Where sql is generated
+enum Engine<'m> {
+    /// A model loaded by this process
+    Local(SqlGenerator<'m>),
+    /// The model of a daemon
+    Daemon(Client),
+}
+
+/// A model, along with the schema and template questions are answered with
+pub struct Natural<'m> {
+    engine: Engine<'m>,
+    dialect: SqlDialect,
+    template: PromptTemplate,
+    schema: String,
+    examples: Vec<Example>,
+    examples_file: Option<PathBuf>,
+    show_prompt: bool,
+    timeout: Option<Duration>,
+    /// Set by Ctrl-C, cancels the generation in progress
+    interrupted: Arc<AtomicBool>,
+}
+
+impl Natural<'_> {
+    /// Answer a question, following up on the previous turns of a conversation
+    pub fn ask(&mut self, question: &str, history: &[Turn]) -> Result<Generation> {
+        let prompt = self.template.render(&PromptContext {
+            schema: self.schema.clone(),
+            question: question.to_string(),
+            dialect: self.dialect.notes(),
+            examples: self.examples.clone(),
+            history: history.to_vec(),
+            ..Default::default()
+        })?;
+
+        self.generate(&prompt)
+    }
+
+    /// Describe what sql computes in plain English
+    pub fn explain(&mut self, sql: &str) -> Result<String> {
+        let prompt = PromptTemplate::explain().render(&PromptContext {
+            schema: self.schema.clone(),
+            dialect: self.dialect.notes(),
+            sql: sql.to_string(),
+            ..Default::default()
+        })?;
+
+        match self.generate(&prompt)?.outcome {
+            Outcome::Explanation(explanation) => Ok(explanation),
+            outcome => bail!("Expected an explanation, got {outcome:?}"),
+        }
+    }
+
+    /// Use a question and its sql as example from now on, and keep it in the examples file
+    pub fn save_example(&mut self, example: Example) -> Result<()> {
+        let Some(path) = &self.examples_file else {
+            bail!("Pass --examples to save examples");
+        };
+
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{}", serde_json::to_string(&example)?)?;
+
+        self.examples.push(example);
+
+        Ok(())
+    }
+
+    pub fn schema(&self) -> &str {
+        &self.schema
+    }
+
+    fn generate(&mut self, prompt: &Prompt) -> Result<Generation> {
+        if self.show_prompt {
+            eprintln!("{prompt}\n");
+        }
+
+        // Ctrl-C outside of generating, e.g. while reading the next question, does not count
+        self.interrupted.store(false, Ordering::Relaxed);
+        let interrupted = || self.interrupted.load(Ordering::Relaxed);
+
+        Ok(match &mut self.engine {
+            Engine::Local(generator) => generator.generate(prompt, self.timeout, interrupted),
+            Engine::Daemon(client) => client.generate(None, prompt, self.timeout, interrupted),
+        }?)
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    if let Command::Ask {
+        options,
+        execute: true,
+        ..
+    } = &cli.command
+    {
+        if options.schema_from.is_none() {
+            Cli::command()
+                .error(
+                    ErrorKind::MissingRequiredArgument,
+                    "--execute requires --schema-from",
+                )
+                .exit();
+        }
+    }
+
+    match run(cli) {
+        Ok(code) => code,
+        Err(e) => {
+            report(&e);
+            ExitCode::from(exit_code(&e))
+        }
+    }
+}
+
+/// Print an error to stderr, along with what can be done about it
+pub fn report(e: &eyre::Report) {
+    let error = e.downcast_ref::<NaturalError>();
+
+    eprintln!("error: {e:#}");
+
+    if let Some(hint) = error.and_then(NaturalError::hint) {
+        eprintln!("hint: {hint}");
+    }
+
+    if let Some(output) = error.and_then(NaturalError::output) {
+        eprintln!("output of the model:\n{output}");
+    }
+}
+
+fn run(cli: Cli) -> Result<ExitCode> {
+    let format = std::env::var("NATURAL_LOG_FORMAT")
+        .ok()
+        .map(|format| format.parse())
+        .transpose()?
+        .unwrap_or_default();
+
+    natural_driver::log::init_stderr(format)?;
+
+    let options = match &cli.command {
+        Command::Ask { options, .. } | Command::Repl { options } => options,
+        Command::Install {
+            repo,
+            file,
+            options,
+        } => return install(repo, file, options).map(|()| ExitCode::SUCCESS),
+    };
+
+    let mut database = options
+        .schema_from
+        .as_deref()
+        .map(Database::connect)
+        .transpose()?;
+
+    let schema = match (&options.schema, &mut database) {
+        (Some(path), _) => std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("failed to read the schema from {}", path.display()))?,
+        (None, Some(database)) => database.schema()?,
+        (None, None) => unreachable!("clap requires --schema or --schema-from"),
+    };
+
+    let template = match (&options.system, &options.user) {
+        (None, None) => PromptTemplate::default(),
+        (system, user) => PromptTemplate::new(
+            "custom",
+            &read_or(system.as_ref(), natural_driver::prompt::DEFAULT_SYSTEM)?,
+            &read_or(user.as_ref(), natural_driver::prompt::DEFAULT_USER)?,
+        )?,
+    };
+
+    let examples = match &options.examples {
+        Some(path) if path.exists() => read_examples(path)?,
+        _ => vec![],
+    };
+
+    let backend;
+    let model;
+
+    let engine = match (&options.daemon, &options.model) {
+        (Some(socket), _) => Engine::Daemon(Client::connect(socket)?.with_dialect(options.dialect)),
+        (None, Some(path)) => {
+            backend = LlamaBackend::init()?;
+            model = load(&backend, path, options)?;
+
+            let context = model
+                .new_context(
+                    &backend,
+                    LlamaContextParams::default()
+                        .with_n_threads(options.threads)
+                        .with_n_ctx(NonZeroU32::new(options.context_size)),
+                )
+                .map_err(|e| NaturalError::ModelLoad {
+                    message: e.to_string(),
+                })?;
+
+            Engine::Local(
+                SqlGenerator::new(context)?
+                    .with_dialect(options.dialect)
+                    .with_chat_format(options.chat_format)
+                    .with_sampling((&options.sampling).into()),
+            )
+        }
+        (None, None) => unreachable!("clap requires --model or --daemon"),
+    };
+
+    // The first Ctrl-C cancels generating, another one before the flag is reset exits
+    let interrupted = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register_conditional_shutdown(
+        SIGINT,
+        exit::INTERRUPTED.into(),
+        interrupted.clone(),
+    )?;
+    signal_hook::flag::register(SIGINT, interrupted.clone())?;
+
+    let mut natural = Natural {
+        engine,
+        dialect: options.dialect,
+        template,
+        schema,
+        examples,
+        examples_file: options.examples.clone(),
+        show_prompt: options.show_prompt,
+        timeout: options.timeout.map(Duration::from_secs),
+        interrupted,
+    };
+
+    match cli.command {
+        Command::Ask {
+            question,
+            format,
+            execute,
+            ..
+        } => {
+            let database = database.as_mut().filter(|_| execute);
+            ask(&mut natural, &question, format, database)
+        }
+        Command::Repl { .. } => {
+            repl::run(&mut natural, database.as_mut()).map(|()| ExitCode::SUCCESS)
+        }
+        Command::Install { .. } => unreachable!("installing needs no model"),
+    }
+}
+
+fn install(repo: &str, file: &str, options: &InstallOptions) -> Result<()> {
+    let sources = match options.sources.as_slice() {
+        [] => vec![hub::default_cache()],
+        sources => sources.to_vec(),
+    };
+
+    let dir = match (&options.models_dir, std::env::var_os("PGDATA")) {
+        (Some(dir), _) => dir.clone(),
+        (None, Some(data)) => PathBuf::from(data).join("natural/models"),
+        (None, None) => bail!("PGDATA is not set, pass --models-dir"),
+    };
+
+    let model = hub::resolve(repo, file, &sources)?;
+    // Registered paths hold for the server, not the working directory
+    let path = std::fs::canonicalize(hub::install(&model, &dir, repo, file)?)?;
+
+    println!("{}", path.display());
+
+    if let Some(url) = &options.register {
+        let name = options
+            .name
+            .clone()
+            .unwrap_or_else(|| hub::model_name(file));
+
+        Database::connect(url)?.register_model(&name, &path, model.sha256.as_deref())?;
+    }
+
+    Ok(())
+}
+
+/// Sql answering a question, along with what is known about how it came about
+#[derive(Serialize)]
+struct Answer<'g> {
+    #[serde(flatten)]
+    generation: &'g Generation,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Rows>,
+}
+
+fn ask(
+    natural: &mut Natural,
+    question: &str,
+    format: Format,
+    database: Option<&mut Database>,
+) -> Result<ExitCode> {
+    let generation = natural.ask(question, &[])?;
+
+    let result = match (&generation.outcome, database) {
+        (Outcome::Sql { sql, .. }, Some(database)) => Some(database.execute(sql)?),
+        _ => None,
+    };
+
+    match format {
+        Format::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&Answer {
+                generation: &generation,
+                result,
+            })?
+        ),
+        Format::Sql => match &generation.outcome {
+            Outcome::Sql { sql, .. } => {
+                println!("{sql}");
+
+                if let Some(result) = result {
+                    print!("\n{result}");
+                }
+            }
+            Outcome::Clarification(clarification) => println!("{clarification}"),
+            Outcome::Explanation(explanation) => println!("{explanation}"),
+        },
+    }
+
+    Ok(match generation.outcome {
+        Outcome::Clarification(_) => ExitCode::from(exit::CLARIFICATION),
+        _ => ExitCode::SUCCESS,
+    })
+}
+
+fn load(backend: &LlamaBackend, path: &Path, options: &Options) -> Result<LlamaModel> {
+    let params = LlamaModelParams::default().with_n_gpu_layers(options.gpu_layers);
+
+    LlamaModel::load_from_file(backend, path, &params).map_err(|e| {
+        NaturalError::ModelLoad {
+            message: format!("{}: {e}", path.display()),
+        }
+        .into()
+    })
+}
+
+/// Content of a template file, `default` if none is given
+fn read_or(path: Option<&PathBuf>, default: &str) -> Result<String> {
+    path.map_or(Ok(default.to_string()), |path| {
+        std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("failed to read the template {}", path.display()))
+    })
+}
+
+/// Examples of a file holding one json object per line
+fn read_examples(path: &Path) -> Result<Vec<Example>> {
+    std::fs::read_to_string(path)?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| Ok(serde_json::from_str(line)?))
+        .collect::<Result<_>>()
+        .wrap_err_with(|| format!("failed to read the examples {}", path.display()))
+}
+
+fn exit_code(e: &eyre::Report) -> u8 {
+    if e.downcast_ref::<postgres::Error>().is_some() {
+        return exit::DATABASE;
+    }
+
+    match e.downcast_ref::<NaturalError>() {
+        Some(NaturalError::ModelLoad { .. } | NaturalError::Unavailable { .. }) => exit::MODEL,
+        Some(NaturalError::ContextOverflow { .. }) => exit::CONTEXT,
+        Some(
+            NaturalError::MissingSqlTag { .. }
+            | NaturalError::Parse { .. }
+            | NaturalError::Validation { .. },
+        ) => exit::OUTPUT,
+        Some(NaturalError::Policy { .. }) => exit::POLICY,
+        Some(NaturalError::Timeout { .. }) => exit::TIMEOUT,
+        Some(NaturalError::Cancelled) => exit::INTERRUPTED,
+        _ => exit::FAILURE,
+    }
+}
@@ -0,0 +1,253 @@
+//! Interactive mode, answering questions as a conversation: the model sees the previous
+//! questions, their sql and how many rows it returned, so follow up questions can refer to them.
+//! Sql is run against the database once confirmed.
+//!
+//! Lines starting with `:` are commands, see [`HELP`]. Input is kept in a history file, so
+//! earlier questions can be recalled and edited across runs.
+
+use std::path::PathBuf;
+
+use eyre::{bail, eyre, Result};
+use natural_driver::extract::Clarification;
+use natural_driver::generator::Outcome;
+use natural_driver::prompt::{Example, Turn};
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+
+use crate::database::Database;
+use crate::Natural;
+
+const PROMPT: &str = "natural> ";
+
+const HELP: &str = "\
+:schema         print the schema questions are answered against
+:explain        explain the last sql in plain English
+:retry          reject the last sql and answer the question again
+:save-example   use the last question and its sql as example from now on
+:clear          forget the conversation
+:help           print this help
+:quit           exit";
+
+struct Repl<'a, 'm> {
+    natural: &'a mut Natural<'m>,
+    database: Option<&'a mut Database>,
+    editor: DefaultEditor,
+    /// Previous questions of the conversation
+    turns: Vec<Turn>,
+    /// Question the model asked back, its options can be answered by number
+    clarification: Option<Clarification>,
+}
+
+pub fn run(natural: &mut Natural, database: Option<&mut Database>) -> Result<()> {
+    let mut repl = Repl {
+        natural,
+        database,
+        editor: DefaultEditor::new()?,
+        turns: vec![],
+        clarification: None,
+    };
+
+    let history = history_file();
+
+    if let Some(path) = &history {
+        // There is no history before the first run
+        let _ = repl.editor.load_history(path);
+    }
+
+    loop {
+        let line = match repl.editor.readline(PROMPT) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+
+        let line = line.trim();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        repl.editor.add_history_entry(line)?;
+
+        let result = match line {
+            ":quit" | ":q" => break,
+            ":help" => {
+                println!("{HELP}");
+                Ok(())
+            }
+            ":schema" => {
+                println!("{}", repl.natural.schema());
+                Ok(())
+            }
+            ":clear" => {
+                repl.turns.clear();
+                repl.clarification = None;
+                Ok(())
+            }
+            ":explain" => repl.explain(),
+            ":retry" => repl.retry(),
+            ":save-example" => repl.save_example(),
+            command if command.starts_with(':') => {
+                Err(eyre!("Unknown command {command}, see :help"))
+            }
+            question => repl.ask(question),
+        };
+
+        if let Err(e) = result {
+            crate::report(&e);
+        }
+    }
+
+    if let Some(path) = &history {
+        repl.editor.save_history(path)?;
+    }
+
+    Ok(())
+}
+
+impl Repl<'_, '_> {
+    fn ask(&mut self, input: &str) -> Result<()> {
+        let question = self.answer(input);
+        let generation = self.natural.ask(&question, &self.turns)?;
+
+        let mut turn = Turn {
+            question,
+            ..Default::default()
+        };
+
+        match generation.outcome {
+            Outcome::Sql {
+                sql, explanation, ..
+            } => {
+                if let Some(explanation) = explanation {
+                    println!("-- {explanation}");
+                }
+
+                println!("{sql}\n");
+
+                turn.summary = self.execute(&sql)?;
+                turn.sql = sql;
+            }
+            Outcome::Clarification(clarification) => {
+                println!("{}", clarification.question);
+
+                for (i, option) in clarification.options.iter().enumerate() {
+                    println!("  {}. {option}", i + 1);
+                }
+
+                println!();
+
+                turn.clarification = Some(clarification.to_string());
+                self.clarification = Some(clarification);
+            }
+            Outcome::Explanation(explanation) => println!("{explanation}\n"),
+        }
+
+        self.turns.push(turn);
+
+        Ok(())
+    }
+
+    /// The option of the clarifying question picked by number, otherwise the input as is
+    fn answer(&mut self, input: &str) -> String {
+        let options = self
+            .clarification
+            .take()
+            .map(|clarification| clarification.options)
+            .unwrap_or_default();
+
+        input
+            .parse::<usize>()
+            .ok()
+            .and_then(|i| options.get(i.checked_sub(1)?).cloned())
+            .unwrap_or_else(|| input.to_string())
+    }
+
+    /// Run sql once confirmed, returning the summary of its result passed to the model
+    fn execute(&mut self, sql: &str) -> Result<Option<String>> {
+        if self.database.is_none() || !self.confirm("Run it? [Y/n] ")? {
+            return Ok(None);
+        }
+
+        let Some(database) = self.database.as_deref_mut() else {
+            return Ok(None);
+        };
+
+        match database.execute(sql) {
+            Ok(rows) => {
+                println!("{}\n", rows.table());
+
+                Ok(Some(match rows.rows.len() {
+                    1 => "1 row".to_string(),
+                    n => format!("{n} rows"),
+                }))
+            }
+            Err(e) => {
+                crate::report(&e);
+                Ok(Some(format!("Failed: {e}")))
+            }
+        }
+    }
+
+    fn confirm(&mut self, prompt: &str) -> Result<bool> {
+        match self.editor.readline(prompt) {
+            Ok(answer) => Ok(matches!(
+                answer.trim().to_ascii_lowercase().as_str(),
+                "" | "y" | "yes"
+            )),
+            Err(ReadlineError::Interrupted | ReadlineError::Eof) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn explain(&mut self) -> Result<()> {
+        let Some(sql) = self.last_sql().map(|turn| turn.sql.clone()) else {
+            bail!("There is no sql to explain yet");
+        };
+
+        println!("{}\n", self.natural.explain(&sql)?);
+
+        Ok(())
+    }
+
+    /// Ask the last question again, telling the model its previous answer was wrong
+    fn retry(&mut self) -> Result<()> {
+        let Some(turn) = self.turns.last_mut() else {
+            bail!("There is no question to retry yet");
+        };
+
+        turn.summary = Some("Rejected by the user, write a different query.".to_string());
+
+        let question = turn.question.clone();
+        self.clarification = None;
+
+        self.ask(&question)
+    }
+
+    fn save_example(&mut self) -> Result<()> {
+        let Some(turn) = self.last_sql() else {
+            bail!("There is no sql to save yet");
+        };
+
+        let example = Example {
+            question: turn.question.clone(),
+            sql: turn.sql.clone(),
+        };
+
+        self.natural.save_example(example)?;
+        println!("Saved the example\n");
+
+        Ok(())
+    }
+
+    /// The last turn answered with sql
+    fn last_sql(&self) -> Option<&Turn> {
+        self.turns.iter().rev().find(|turn| !turn.sql.is_empty())
+    }
+}
+
+/// `~/.natural_history`, `None` without a home directory
+fn history_file() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".natural_history"))
+}
@@ -0,0 +1,251 @@
+//! Formatting of prompts with the chat template of the loaded model.
+//!
+//! Instruct models expect their turns framed exactly like during training,
+//! e.g. `[INST]` for Mistral or `<|im_start|>` for Qwen. The template is read
+//! from the `tokenizer.chat_template` GGUF metadata and applied by llama.cpp.
+//! Templates llama.cpp does not recognise fall back to a built-in rendering of
+//! the detected family, models without any template get plain text.
+
+use std::fmt;
+use std::str::FromStr;
+
+use eyre::{bail, Result};
+use llama_cpp_2::model::{AddBos, LlamaChatMessage, LlamaModel, Special};
+use serde::{Deserialize, Serialize};
+
+use crate::prompt::{Prompt, Role};
+
+/// GGUF metadata key holding the jinja chat template
+pub const CHAT_TEMPLATE_KEY: &str = "tokenizer.chat_template";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatFormat {
+    /// Use the template embedded in the model
+    #[default]
+    Auto,
+    /// `[INST] ... [/INST]` as used by Mistral and Llama 2
+    Mistral,
+    /// `<|im_start|>role ... <|im_end|>` as used by Qwen and many fine-tunes
+    ChatMl,
+    /// `<|start_header_id|>role<|end_header_id|>` as used by Llama 3
+    Llama3,
+    /// No framing, for base and completion models such as SQLCoder
+    Plain,
+}
+
+impl ChatFormat {
+    /// Guess the family of a jinja chat template from the markers it emits
+    pub fn detect(template: &str) -> Self {
+        if template.contains("<|start_header_id|>") {
+            Self::Llama3
+        } else if template.contains("<|im_start|>") {
+            Self::ChatMl
+        } else if template.contains("[INST]") {
+            Self::Mistral
+        } else {
+            Self::Plain
+        }
+    }
+
+    /// Render the prompt with the built-in framing of this family
+    pub fn apply(&self, prompt: &Prompt) -> String {
+        let mut out = String::new();
+
+        match self {
+            Self::Auto | Self::Plain => out.push_str(&prompt.to_string()),
+            Self::Mistral => {
+                // Mistral has no system role, it is merged into the first user turn
+                let mut system = None;
+
+                for message in &prompt.messages {
+                    match message.role {
+                        Role::System => system = Some(message.content.as_str()),
+                        Role::User => {
+                            out.push_str("[INST] ");
+
+                            if let Some(system) = system.take() {
+                                out.push_str(system);
+                                out.push_str("\n\n");
+                            }
+
+                            out.push_str(&message.content);
+                            out.push_str(" [/INST]");
+                        }
+                        Role::Assistant => {
+                            out.push_str(&message.content);
+                            out.push_str("</s>");
+                        }
+                    }
+                }
+            }
+            Self::ChatMl => {
+                for message in &prompt.messages {
+                    out.push_str(&format!(
+                        "<|im_start|>{}\n{}<|im_end|>\n",
+                        message.role, message.content
+                    ));
+                }
+
+                out.push_str("<|im_start|>assistant\n");
+            }
+            Self::Llama3 => {
+                for message in &prompt.messages {
+                    out.push_str(&format!(
+                        "<|start_header_id|>{}<|end_header_id|>\n\n{}<|eot_id|>",
+                        message.role, message.content
+                    ));
+                }
+
+                out.push_str("<|start_header_id|>assistant<|end_header_id|>\n\n");
+            }
+        }
+
+        out
+    }
+}
+
+impl fmt::Display for ChatFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Auto => "auto",
+            Self::Mistral => "mistral",
+            Self::ChatMl => "chatml",
+            Self::Llama3 => "llama3",
+            Self::Plain => "plain",
+        })
+    }
+}
+
+impl FromStr for ChatFormat {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "auto" | "" => Self::Auto,
+            "mistral" | "llama2" => Self::Mistral,
+            "chatml" | "qwen" => Self::ChatMl,
+            "llama3" => Self::Llama3,
+            "plain" | "none" => Self::Plain,
+            other => bail!("Unknown chat format {other:?}"),
+        })
+    }
+}
+
+/// The jinja chat template embedded in the model, if any
+pub fn template(model: &LlamaModel) -> Option<String> {
+    model
+        .meta_val_str(CHAT_TEMPLATE_KEY)
+        .ok()
+        .filter(|t| !t.is_empty())
+}
+
+/// Format a prompt for the model, returning the text and whether a BOS token must be prepended.
+pub fn format(model: &LlamaModel, prompt: &Prompt, format: ChatFormat) -> Result<(String, AddBos)> {
+    let text = match format {
+        ChatFormat::Auto => match template(model) {
+            Some(template) => apply_model_template(model, prompt)
+                .unwrap_or_else(|_| ChatFormat::detect(&template).apply(prompt)),
+            None => ChatFormat::Plain.apply(prompt),
+        },
+        format => format.apply(prompt),
+    };
+
+    // Models without a BOS token, or without a text for it, leave it to add_bos_token
+    let bos = Some(model.token_bos())
+        .filter(|bos| bos.0 >= 0)
+        .and_then(|bos| model.token_to_str(bos, Special::Tokenize).ok())
+        .unwrap_or_default();
+    let wants_bos = model
+        .meta_val_str("tokenizer.ggml.add_bos_token")
+        .map_or(true, |v| v != "false");
+
+    let add_bos = add_bos(&text, &bos, wants_bos);
+
+    Ok((text, add_bos))
+}
+
+/// Templates usually start with the BOS token themselves, adding another one degrades the
+/// output of most instruct models
+fn add_bos(text: &str, bos: &str, wants_bos: bool) -> AddBos {
+    if !wants_bos || (!bos.is_empty() && text.starts_with(bos)) {
+        AddBos::Never
+    } else {
+        AddBos::Always
+    }
+}
+
+fn apply_model_template(model: &LlamaModel, prompt: &Prompt) -> Result<String> {
+    let template = model.chat_template(None)?;
+
+    let messages = prompt
+        .messages
+        .iter()
+        .map(|m| LlamaChatMessage::new(m.role.to_string(), m.content.clone()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(model.apply_chat_template(&template, &messages, true)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prompt::Message;
+
+    fn prompt() -> Prompt {
+        Prompt {
+            messages: vec![
+                Message {
+                    role: Role::System,
+                    content: "Be brief.".into(),
+                },
+                Message {
+                    role: Role::User,
+                    content: "Count users".into(),
+                },
+            ],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn detects_template_families() {
+        let mistral = "{{ bos_token }}{% for message in messages %}[INST] {{ message['content'] }} [/INST]{% endfor %}";
+        let chatml = "{% for message in messages %}<|im_start|>{{ message['role'] }}\n{{ message['content'] }}<|im_end|>{% endfor %}";
+        let llama3 = "{{ '<|start_header_id|>' + message['role'] + '<|end_header_id|>' }}";
+
+        assert_eq!(ChatFormat::detect(mistral), ChatFormat::Mistral);
+        assert_eq!(ChatFormat::detect(chatml), ChatFormat::ChatMl);
+        assert_eq!(ChatFormat::detect(llama3), ChatFormat::Llama3);
+        assert_eq!(ChatFormat::detect("{{ prompt }}"), ChatFormat::Plain);
+    }
+
+    #[test]
+    fn adds_bos_unless_the_template_did() {
+        let text = "<s>[INST] Count users [/INST]";
+
+        assert!(matches!(add_bos(text, "<s>", true), AddBos::Never));
+        assert!(matches!(
+            add_bos("[INST] Count users [/INST]", "<s>", true),
+            AddBos::Always
+        ));
+        assert!(matches!(add_bos(text, "", true), AddBos::Always));
+        assert!(matches!(add_bos(text, "", false), AddBos::Never));
+    }
+
+    #[test]
+    fn mistral_merges_system_into_first_turn() {
+        assert_eq!(
+            ChatFormat::Mistral.apply(&prompt()),
+            "[INST] Be brief.\n\nCount users [/INST]"
+        );
+    }
+
+    #[test]
+    fn chatml_opens_assistant_turn() {
+        assert_eq!(
+            ChatFormat::ChatMl.apply(&prompt()),
+            "<|im_start|>system\nBe brief.<|im_end|>\n<|im_start|>user\nCount users<|im_end|>\n<|im_start|>assistant\n"
+        );
+    }
+}
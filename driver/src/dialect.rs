@@ -0,0 +1,246 @@
+//! Sql dialects the driver generates and validates sql for.
+//!
+//! Models mix up dialects easily, writing `TOP` instead of `LIMIT` or postgres date arithmetic
+//! for sqlite. Besides choosing the parser, a dialect therefore tells the model how common
+//! constructs are written in it, and shows a few questions answered in it. These examples use
+//! tables of their own, examples for the schema at hand are
+//! [`PromptContext::examples`](crate::prompt::PromptContext::examples).
+
+use std::fmt;
+use std::str::FromStr;
+
+use eyre::{bail, Result};
+use serde::{Deserialize, Serialize};
+use sqlparser::dialect::{Dialect, DuckDbDialect, PostgreSqlDialect, SQLiteDialect};
+
+use crate::prompt::Example;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SqlDialect {
+    #[default]
+    Postgres,
+    DuckDb,
+    Sqlite,
+}
+
+impl SqlDialect {
+    /// Parser validating sql of this dialect
+    pub fn parser(&self) -> &'static dyn Dialect {
+        match self {
+            Self::Postgres => &PostgreSqlDialect {},
+            Self::DuckDb => &DuckDbDialect {},
+            Self::Sqlite => &SQLiteDialect {},
+        }
+    }
+
+    /// Dialect notes of prompts, i.e. the value of `{{dialect}}`
+    pub fn notes(&self) -> String {
+        let (name, hints) = match self {
+            Self::Postgres => ("postgres", POSTGRES),
+            Self::DuckDb => ("DuckDB", DUCKDB),
+            Self::Sqlite => ("SQLite", SQLITE),
+        };
+
+        let mut notes = format!("Output SQL must be {name} compliant.");
+
+        for (what, example) in hints {
+            notes.push_str(&format!("\n- {what}: {example}"));
+        }
+
+        notes.push_str(&format!(
+            "\nExamples of {name} SQL for the tables {EXAMPLE_TABLES}, \
+             which are not part of the schema:"
+        ));
+
+        for example in self.examples() {
+            notes.push_str(&format!("\n- {}\n  {}", example.question, example.sql));
+        }
+
+        notes
+    }
+
+    /// Questions answered in this dialect, showing its date functions, string operators and
+    /// row limits in full queries
+    pub fn examples(&self) -> Vec<Example> {
+        let examples = match self {
+            Self::Postgres => POSTGRES_EXAMPLES,
+            Self::DuckDb => DUCKDB_EXAMPLES,
+            Self::Sqlite => SQLITE_EXAMPLES,
+        };
+
+        examples
+            .iter()
+            .map(|(question, sql)| Example {
+                question: question.to_string(),
+                sql: sql.to_string(),
+            })
+            .collect()
+    }
+}
+
+/// How common constructs are written, as pairs of construct and example
+type Hints = &'static [(&'static str, &'static str)];
+
+const POSTGRES: Hints = &[
+    (
+        "Dates",
+        "now(), current_date, date_trunc('month', created_at), \
+         created_at >= now() - interval '7 days'",
+    ),
+    ("String concatenation", "first_name || ' ' || last_name"),
+    ("Case insensitive matching", "name ILIKE '%henry%'"),
+    ("Row limits", "LIMIT 10, never TOP 10"),
+];
+
+const DUCKDB: Hints = &[
+    (
+        "Dates",
+        "current_date, date_trunc('month', created_at), \
+         created_at >= current_date - INTERVAL 7 DAY",
+    ),
+    ("String concatenation", "first_name || ' ' || last_name"),
+    ("Case insensitive matching", "name ILIKE '%henry%'"),
+    ("Row limits", "LIMIT 10, never TOP 10"),
+];
+
+const SQLITE: Hints = &[
+    (
+        "Dates are stored as text",
+        "date('now'), strftime('%Y-%m', created_at), created_at >= date('now', '-7 days')",
+    ),
+    ("String concatenation", "first_name || ' ' || last_name"),
+    (
+        "Case insensitive matching",
+        "name LIKE '%henry%', there is no ILIKE",
+    ),
+    ("Row limits", "LIMIT 10, never TOP 10"),
+];
+
+/// Tables the examples of all dialects are written for
+const EXAMPLE_TABLES: &str = "orders(id, customer_id, total, created_at) and \
+     customers(id, first_name, last_name, email)";
+
+/// Pairs of question and sql
+type Examples = &'static [(&'static str, &'static str)];
+
+const POSTGRES_EXAMPLES: Examples = &[
+    (
+        "How many orders were placed in each of the last three months?",
+        "SELECT date_trunc('month', created_at) AS month, count(*) FROM orders \
+         WHERE created_at >= date_trunc('month', now()) - interval '2 months' \
+         GROUP BY 1 ORDER BY 1",
+    ),
+    (
+        "Full names of the customers with a gmail address",
+        "SELECT first_name || ' ' || last_name AS name FROM customers \
+         WHERE email ILIKE '%@gmail.com'",
+    ),
+    (
+        "The five largest orders of the last week",
+        "SELECT * FROM orders WHERE created_at >= now() - interval '7 days' \
+         ORDER BY total DESC LIMIT 5",
+    ),
+];
+
+const DUCKDB_EXAMPLES: Examples = &[
+    (
+        "How many orders were placed in each of the last three months?",
+        "SELECT date_trunc('month', created_at) AS month, count(*) FROM orders \
+         WHERE created_at >= date_trunc('month', current_date) - INTERVAL 2 MONTH \
+         GROUP BY ALL ORDER BY 1",
+    ),
+    (
+        "Full names of the customers with a gmail address",
+        "SELECT first_name || ' ' || last_name AS name FROM customers \
+         WHERE email ILIKE '%@gmail.com'",
+    ),
+    (
+        "The five largest orders of the last week",
+        "SELECT * FROM orders WHERE created_at >= current_date - INTERVAL 7 DAY \
+         ORDER BY total DESC LIMIT 5",
+    ),
+];
+
+const SQLITE_EXAMPLES: Examples = &[
+    (
+        "How many orders were placed in each of the last three months?",
+        "SELECT strftime('%Y-%m', created_at) AS month, count(*) FROM orders \
+         WHERE created_at >= date('now', 'start of month', '-2 months') \
+         GROUP BY 1 ORDER BY 1",
+    ),
+    (
+        "Full names of the customers with a gmail address",
+        "SELECT first_name || ' ' || last_name AS name FROM customers \
+         WHERE lower(email) LIKE '%@gmail.com'",
+    ),
+    (
+        "The five largest orders of the last week",
+        "SELECT * FROM orders WHERE created_at >= date('now', '-7 days') \
+         ORDER BY total DESC LIMIT 5",
+    ),
+];
+
+impl fmt::Display for SqlDialect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Postgres => "postgres",
+            Self::DuckDb => "duckdb",
+            Self::Sqlite => "sqlite",
+        })
+    }
+}
+
+impl FromStr for SqlDialect {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "postgres" | "postgresql" => Self::Postgres,
+            "duckdb" => Self::DuckDb,
+            "sqlite" => Self::Sqlite,
+            other => bail!("Unknown sql dialect {other:?}, expected postgres, duckdb or sqlite"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlparser::parser::Parser;
+
+    use super::*;
+
+    #[test]
+    fn parses_with_the_dialect() {
+        let parses = |dialect: SqlDialect, sql| Parser::parse_sql(dialect.parser(), sql).is_ok();
+
+        let sql = "SELECT * EXCLUDE (email) FROM users";
+        assert!(parses(SqlDialect::DuckDb, sql));
+        assert!(!parses(SqlDialect::Postgres, sql));
+
+        assert_eq!(
+            "PostgreSQL".parse::<SqlDialect>().unwrap(),
+            SqlDialect::Postgres
+        );
+        assert!("mssql".parse::<SqlDialect>().is_err());
+
+        for dialect in [SqlDialect::Postgres, SqlDialect::DuckDb, SqlDialect::Sqlite] {
+            assert_eq!(dialect.to_string().parse::<SqlDialect>().unwrap(), dialect);
+            assert!(dialect.notes().contains("LIMIT"));
+        }
+    }
+
+    #[test]
+    fn examples_are_valid_in_their_dialect() {
+        for dialect in [SqlDialect::Postgres, SqlDialect::DuckDb, SqlDialect::Sqlite] {
+            for example in dialect.examples() {
+                assert!(
+                    Parser::parse_sql(dialect.parser(), &example.sql).is_ok(),
+                    "{dialect}: {}",
+                    example.sql
+                );
+                assert!(dialect.notes().contains(&example.sql));
+            }
+        }
+    }
+}
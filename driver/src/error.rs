@@ -0,0 +1,121 @@
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Why sql could not be generated. Errors carrying the raw model output expose it through
+/// [`NaturalError::output`], so callers can show what the model answered.
+#[derive(Clone, Debug, PartialEq, Eq, Error, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum NaturalError {
+    #[error("failed to load the model: {message}")]
+    ModelLoad { message: String },
+
+    #[error("{tokens} tokens exceed the context of {capacity} tokens")]
+    ContextOverflow { tokens: usize, capacity: usize },
+
+    #[error("failed to evaluate the model: {message}")]
+    Decode { message: String },
+
+    #[error("model output is missing the {tag} tag")]
+    MissingSqlTag { tag: String, output: String },
+
+    #[error("model output is not valid sql: {message}")]
+    Parse { message: String, output: String },
+
+    #[error("model output is rejected: {message}")]
+    Validation { message: String, output: String },
+
+    #[error("generated sql violates policy: {message}")]
+    Policy { message: String, output: String },
+
+    #[error("generation timed out after {ms} ms")]
+    Timeout { ms: u64 },
+
+    #[error("generation was cancelled")]
+    Cancelled,
+
+    #[error("inference server is unavailable: {message}")]
+    Unavailable { message: String },
+
+    #[error("{message}")]
+    Internal { message: String },
+}
+
+impl NaturalError {
+    pub fn internal(e: impl Display) -> Self {
+        Self::Internal {
+            message: format!("{e:#}"),
+        }
+    }
+
+    pub fn unavailable(e: impl Display) -> Self {
+        Self::Unavailable {
+            message: format!("{e:#}"),
+        }
+    }
+
+    /// Raw output of the model, if it got to answer
+    pub fn output(&self) -> Option<&str> {
+        match self {
+            Self::MissingSqlTag { output, .. }
+            | Self::Parse { output, .. }
+            | Self::Validation { output, .. }
+            | Self::Policy { output, .. } => Some(output),
+            _ => None,
+        }
+    }
+
+    /// What the caller can do about the error
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            Self::ModelLoad { .. } => Some("Check that natural.model_path points to a GGUF model."),
+            Self::ContextOverflow { .. } => {
+                Some("Shorten the question or the schema, or serve fewer requests in parallel.")
+            }
+            Self::MissingSqlTag { .. } | Self::Parse { .. } | Self::Validation { .. } => {
+                Some("The model answered in an unexpected way, rephrasing the question may help.")
+            }
+            Self::Policy { .. } => Some(
+                "Ask a more specific question, or raise natural.max_plan_cost and natural.max_rows.",
+            ),
+            Self::Timeout { .. } => {
+                Some("Simplify the question or raise natural.generation_timeout.")
+            }
+            Self::Unavailable { .. } => Some(
+                "The inference worker requires natural in shared_preload_libraries, the daemon \
+                 backend a daemon listening on natural.daemon_socket.",
+            ),
+            Self::Decode { .. } | Self::Cancelled | Self::Internal { .. } => None,
+        }
+    }
+}
+
+impl From<eyre::Report> for NaturalError {
+    fn from(report: eyre::Report) -> Self {
+        match report.downcast::<NaturalError>() {
+            Ok(e) => e,
+            Err(report) => Self::internal(report),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn survives_eyre_and_the_wire() {
+        let error = NaturalError::MissingSqlTag {
+            tag: "</sql>".to_string(),
+            output: "<sql>SELECT 1".to_string(),
+        };
+
+        let report = eyre::Report::new(error.clone()).wrap_err("while generating");
+        assert_eq!(NaturalError::from(report), error);
+
+        let json = serde_json::to_string(&error).unwrap();
+        assert_eq!(serde_json::from_str::<NaturalError>(&json).unwrap(), error);
+        assert_eq!(error.output(), Some("<sql>SELECT 1"));
+    }
+}
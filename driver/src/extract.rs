@@ -0,0 +1,356 @@
+//! Locating the sql, and the explanation of it, in the output of a model.
+//!
+//! Models are asked to answer in `<sql>` tags but do not always comply: they add prose, change
+//! the case of the tags, repeat the query or use markdown fences instead. The strategies are
+//! tried in order, the first one finding sql wins:
+//!
+//! 1. the last complete `<sql>` block, ignoring case
+//! 2. the last complete fenced code block that is sql or untagged
+//! 3. the longest prefix parsing as a single statement, starting at any sql keyword
+//!
+//! Instead of sql, models may ask back in a `<clarify>` block when a question is ambiguous.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use sqlparser::dialect::Dialect;
+use sqlparser::parser::Parser;
+
+/// Keywords a statement may start with
+const KEYWORDS: &[&str] = &["select", "with", "insert", "update", "delete"];
+
+/// Languages of fenced code blocks that may contain sql
+const FENCE_LANGUAGES: &[&str] = &[
+    "",
+    "sql",
+    "postgres",
+    "postgresql",
+    "psql",
+    "duckdb",
+    "sqlite",
+];
+
+/// Question the model asks back instead of guessing, along with the answers it suggests
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Clarification {
+    pub question: String,
+    pub options: Vec<String>,
+}
+
+impl fmt::Display for Clarification {
+    /// The `<clarify>` block the model answered with
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "<clarify>")?;
+        writeln!(f, "<question>{}</question>", self.question)?;
+
+        for option in &self.options {
+            writeln!(f, "<option>{option}</option>")?;
+        }
+
+        write!(f, "</clarify>")
+    }
+}
+
+/// Find the sql in the output of a model, `None` if it contains none
+pub fn extract(output: &str, dialect: &dyn Dialect) -> Option<String> {
+    tagged(output)
+        .or_else(|| fenced(output))
+        .or_else(|| parsable_prefix(output, dialect))
+}
+
+/// Find the explanation the model gave along with the sql, `None` if it gave none
+pub fn explanation(output: &str) -> Option<String> {
+    block(output, "explanation").and_then(non_empty)
+}
+
+/// Find the clarifying question of a model, `None` if it did not ask one
+pub fn clarification(output: &str) -> Option<Clarification> {
+    let body = block(output, "clarify")?;
+
+    let options = blocks(body, "option")
+        .into_iter()
+        .filter_map(non_empty)
+        .collect();
+
+    // Models may leave out the question tag and write it in front of the options
+    let question = block(body, "question").or_else(|| {
+        let lower = body.to_ascii_lowercase();
+        Some(&body[..lower.find("<option>").unwrap_or(body.len())])
+    })?;
+
+    Some(Clarification {
+        question: non_empty(question)?,
+        options,
+    })
+}
+
+/// Content of the last complete block enclosed in `tag`, ignoring case
+fn block<'a>(output: &'a str, tag: &str) -> Option<&'a str> {
+    let lower = output.to_ascii_lowercase();
+    let (open, close) = (format!("<{tag}>"), format!("</{tag}>"));
+
+    let end = lower.rfind(&close)?;
+    let start = lower[..end].rfind(&open)? + open.len();
+
+    Some(&output[start..end])
+}
+
+/// Contents of all complete blocks enclosed in `tag`, in order and ignoring case
+fn blocks<'a>(output: &'a str, tag: &str) -> Vec<&'a str> {
+    let lower = output.to_ascii_lowercase();
+    let (open, close) = (format!("<{tag}>"), format!("</{tag}>"));
+
+    let mut blocks = vec![];
+    let mut from = 0;
+
+    while let Some(start) = lower[from..].find(&open).map(|i| from + i + open.len()) {
+        let Some(end) = lower[start..].find(&close).map(|i| start + i) else {
+            break;
+        };
+
+        blocks.push(&output[start..end]);
+        from = end + close.len();
+    }
+
+    blocks
+}
+
+/// Content of the last complete `<sql>` block, without markdown fences
+fn tagged(output: &str) -> Option<String> {
+    let sql = block(output, "sql")?
+        .lines()
+        .filter(|line| !line.trim_start().starts_with("```"))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    non_empty(&sql)
+}
+
+/// Content of the last complete fenced code block holding sql
+fn fenced(output: &str) -> Option<String> {
+    let mut blocks = vec![];
+    let mut open: Option<(bool, Vec<&str>)> = None;
+
+    for line in output.lines() {
+        let trimmed = line.trim();
+
+        match (open.take(), trimmed.strip_prefix("```")) {
+            (None, Some(language)) => {
+                let language = language.trim().to_ascii_lowercase();
+                open = Some((FENCE_LANGUAGES.contains(&language.as_str()), vec![]));
+            }
+            (None, None) => {}
+            (Some((sql, body)), Some(_)) => {
+                if sql {
+                    blocks.push(body.join("\n"));
+                }
+            }
+            (Some((sql, mut body)), None) => {
+                body.push(line);
+                open = Some((sql, body));
+            }
+        }
+    }
+
+    blocks.iter().rev().find_map(|block| non_empty(block))
+}
+
+/// Longest text parsing as a single statement, starting at a keyword and ending at the end of a
+/// line or statement
+fn parsable_prefix(output: &str, dialect: &dyn Dialect) -> Option<String> {
+    let lower = output.to_ascii_lowercase();
+    let mut best: Option<&str> = None;
+
+    for start in KEYWORDS
+        .iter()
+        .flat_map(|keyword| keyword_positions(&lower, keyword))
+    {
+        let rest = &output[start..];
+
+        let mut ends = rest
+            .char_indices()
+            .filter_map(|(i, c)| match c {
+                ';' => Some(i + 1),
+                '\n' => Some(i),
+                _ => None,
+            })
+            .chain([rest.len()])
+            .collect::<Vec<_>>();
+
+        ends.sort_unstable();
+        ends.dedup();
+
+        for end in ends.into_iter().rev() {
+            let candidate = rest[..end].trim();
+
+            if best.is_some_and(|best| best.len() >= candidate.len()) {
+                break;
+            }
+
+            if parses(candidate, dialect) {
+                best = Some(candidate);
+                break;
+            }
+        }
+    }
+
+    best.and_then(non_empty)
+}
+
+/// Byte offsets of `keyword` in `lower` where it is a whole word
+fn keyword_positions<'a>(lower: &'a str, keyword: &'a str) -> impl Iterator<Item = usize> + 'a {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+
+    lower.match_indices(keyword).filter_map(move |(i, _)| {
+        let before = lower[..i].chars().next_back();
+        let after = lower[i + keyword.len()..].chars().next();
+
+        (!before.is_some_and(is_word) && !after.is_some_and(is_word)).then_some(i)
+    })
+}
+
+fn parses(sql: &str, dialect: &dyn Dialect) -> bool {
+    Parser::parse_sql(dialect, sql).is_ok_and(|statements| statements.len() == 1)
+}
+
+fn non_empty(sql: &str) -> Option<String> {
+    Some(sql.trim().to_string()).filter(|sql| !sql.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlparser::dialect::PostgreSqlDialect;
+
+    use super::*;
+
+    fn sql(output: &str) -> Option<String> {
+        extract(output, &PostgreSqlDialect {})
+    }
+
+    #[test]
+    fn tagged_blocks() {
+        assert_eq!(
+            sql("<sql>\nSELECT * FROM users WHERE name = 'henry'\n</sql>").as_deref(),
+            Some("SELECT * FROM users WHERE name = 'henry'")
+        );
+
+        assert_eq!(
+            sql(
+                "Sure! Here is the query you asked for:\n\n<SQL>SELECT count(*) FROM orders;</SQL>"
+            )
+            .as_deref(),
+            Some("SELECT count(*) FROM orders;")
+        );
+
+        assert_eq!(
+            sql("<sql>\n```sql\nSELECT id FROM users\n```\n</sql>").as_deref(),
+            Some("SELECT id FROM users")
+        );
+    }
+
+    #[test]
+    fn picks_the_last_complete_block() {
+        let output = "<sql>SELECT name FROM user</sql>\n\
+                      Oops, the table is called users:\n\
+                      <sql>SELECT name FROM users</sql>\n\
+                      <sql>SELECT";
+
+        assert_eq!(sql(output).as_deref(), Some("SELECT name FROM users"));
+    }
+
+    #[test]
+    fn fenced_blocks_without_tags() {
+        let output = "To find henry, filter on the name column:\n\
+                      ```sql\n\
+                      SELECT *\n\
+                      FROM users\n\
+                      WHERE name = 'henry';\n\
+                      ```\n\
+                      This returns every user named henry.";
+
+        assert_eq!(
+            sql(output).as_deref(),
+            Some("SELECT *\nFROM users\nWHERE name = 'henry';")
+        );
+
+        let output = "```python\nprint('SELECT 1')\n```\n```\nSELECT 2\n```";
+        assert_eq!(sql(output).as_deref(), Some("SELECT 2"));
+    }
+
+    #[test]
+    fn falls_back_to_the_longest_parsable_statement() {
+        let output = "The following query selects all users named henry:\n\
+                      SELECT * FROM users\n\
+                      WHERE name = 'henry'\n\
+                      It uses a simple filter on the name column.";
+
+        assert_eq!(
+            sql(output).as_deref(),
+            Some("SELECT * FROM users\nWHERE name = 'henry'")
+        );
+
+        let output = "with recent as (select * from orders) select count(*) from recent; done";
+        assert_eq!(
+            sql(output).as_deref(),
+            Some("with recent as (select * from orders) select count(*) from recent;")
+        );
+    }
+
+    #[test]
+    fn explanations_next_to_the_sql() {
+        let output = "<explanation>\nCounts the orders of every product.\n</explanation>\n\
+                      <sql>SELECT product, count(*) FROM orders GROUP BY product</sql>";
+
+        assert_eq!(
+            explanation(output).as_deref(),
+            Some("Counts the orders of every product.")
+        );
+        assert_eq!(
+            sql(output).as_deref(),
+            Some("SELECT product, count(*) FROM orders GROUP BY product")
+        );
+        assert_eq!(explanation("<sql>SELECT 1</sql>"), None);
+    }
+
+    #[test]
+    fn clarifying_questions() {
+        let output = "<clarify>\n\
+                      <question>Top customers by revenue or by number of orders?</question>\n\
+                      <option>by revenue</option>\n\
+                      <OPTION>by number of orders</OPTION>\n\
+                      </clarify>";
+
+        let clarification = clarification(output).unwrap();
+
+        assert_eq!(
+            clarification,
+            Clarification {
+                question: "Top customers by revenue or by number of orders?".into(),
+                options: vec!["by revenue".into(), "by number of orders".into()],
+            }
+        );
+        assert_eq!(
+            super::clarification(&clarification.to_string()),
+            Some(clarification)
+        );
+
+        assert_eq!(
+            super::clarification("<clarify>Which year?</clarify>"),
+            Some(Clarification {
+                question: "Which year?".into(),
+                options: vec![],
+            })
+        );
+        assert_eq!(super::clarification("<sql>SELECT 1</sql>"), None);
+    }
+
+    #[test]
+    fn finds_nothing_in_prose() {
+        assert_eq!(
+            sql("I cannot answer this question with the given schema."),
+            None
+        );
+        assert_eq!(sql("<sql>SELECT * FROM users WHERE"), None);
+        assert_eq!(sql("<sql></sql>"), None);
+    }
+}
@@ -1,144 +1,912 @@
 use llama_cpp_2::context::LlamaContext;
 use llama_cpp_2::ggml_time_us;
-use sqlparser::ast::Statement;
-use std::time::Duration;
+use llama_cpp_2::token::LlamaToken;
+use std::cmp::Reverse;
+use std::ops::AddAssign;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
-use eyre::{ensure, eyre, Context, ContextCompat, Result};
+use eyre::{Context, Result};
 use llama_cpp_2::llama_batch::LlamaBatch;
-use llama_cpp_2::model::{AddBos, Special};
+use llama_cpp_2::model::Special;
 use llama_cpp_2::sampling::LlamaSampler;
-use sqlparser::dialect::PostgreSqlDialect;
+use serde::{Deserialize, Serialize};
 use sqlparser::parser::Parser;
+use tracing::{debug, error, warn};
+
+use crate::chat::{self, ChatFormat};
+use crate::dialect::SqlDialect;
+use crate::error::NaturalError;
+use crate::extract::{self, Clarification};
+use crate::inspect;
+use crate::prompt::{Prompt, Task};
+
+/// Maximum number of tokens generated per request, unless configured otherwise
+const MAX_TOKENS: usize = 1024;
+
+/// Identifies a request submitted to the generator
+pub type Ticket = u64;
+
+/// Answer generated for a prompt, along with what the model produced to arrive at it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Generation {
+    pub outcome: Outcome,
+    /// Raw output of the model
+    pub output: String,
+    pub prompt_tokens: usize,
+    /// Prompt tokens reused from the kv cache instead of being evaluated
+    pub cached_tokens: usize,
+    pub generated_tokens: usize,
+    pub timings: Timings,
+    /// Geometric mean of the probabilities of the generated tokens, between 0 and 1
+    pub confidence: f64,
+}
+
+/// How the next token is chosen from the probabilities the model predicts
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Sampling {
+    /// Randomness of the choice, 0 always picks the most likely token
+    pub temperature: f32,
+    /// Only choose from the `top_k` most likely tokens, 0 to consider all
+    pub top_k: i32,
+    /// Only choose from the most likely tokens whose probabilities add up to `top_p`
+    pub top_p: f32,
+    pub seed: u32,
+    /// Maximum number of tokens generated per request
+    pub max_tokens: usize,
+}
+
+impl Sampling {
+    fn sampler(&self) -> LlamaSampler {
+        if self.temperature <= 0.0 {
+            return LlamaSampler::greedy();
+        }
+
+        let mut samplers = vec![];
+
+        if self.top_k > 0 {
+            samplers.push(LlamaSampler::top_k(self.top_k));
+        }
+
+        samplers.extend([
+            LlamaSampler::top_p(self.top_p, 1),
+            LlamaSampler::temp(self.temperature),
+            LlamaSampler::dist(self.seed),
+        ]);
+
+        LlamaSampler::chain_simple(samplers)
+    }
+}
 
-const PROMPT: &str = r#"
-You are an expert SQL query generator that converts natural language to SQL.
+impl Default for Sampling {
+    fn default() -> Self {
+        Self {
+            temperature: 0.0,
+            top_k: 40,
+            top_p: 0.95,
+            seed: 1234,
+            max_tokens: MAX_TOKENS,
+        }
+    }
+}
 
-<schema>{SCHEMA}</schema>
+/// What the model answered with
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Outcome {
+    Sql {
+        sql: String,
+        /// What the sql computes, in the words of the model
+        explanation: Option<String>,
+        /// Tables the sql reads from or writes to
+        tables: Vec<String>,
+    },
+    /// The question is ambiguous, the model asks back instead of guessing
+    Clarification(Clarification),
+    /// What the sql of the prompt computes, see [`Task::Explain`]
+    Explanation(String),
+}
 
-<question>{QUESTION}</question>
+impl Outcome {
+    pub fn sql(&self) -> Option<&str> {
+        match self {
+            Self::Sql { sql, .. } => Some(sql),
+            Self::Clarification(_) | Self::Explanation(_) => None,
+        }
+    }
+}
 
-Based on the schema, generate the most efficient SQL query that answers the question.
-You can only reference tables and columns outlined in the schema!
-You MUST NOT generate any WORDS beyond valid SQL. Output SQL must be postgres compliant.
+/// Where the time of a request went, in milliseconds
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct Timings {
+    /// Waiting for a free slot, only known to a server queueing requests
+    pub queue_ms: f64,
+    /// Evaluating the part of the prompt that was not cached
+    pub prompt_ms: f64,
+    /// Generating the answer
+    pub generation_ms: f64,
+}
 
-<sql>
-[YOUR OUTPUT SQL QUERY]
-</sql>
-"#;
+impl AddAssign for Timings {
+    fn add_assign(&mut self, other: Self) {
+        self.queue_ms += other.queue_ms;
+        self.prompt_ms += other.prompt_ms;
+        self.generation_ms += other.generation_ms;
+    }
+}
 
+/// Generates sql for several requests at once.
+///
+/// Every request occupies a slot, i.e. a sequence id in the kv cache of the context. Each
+/// [`SqlGenerator::step`] decodes one batch containing the next token of every generating
+/// request and a chunk of every prompt still being evaluated, so requests join and leave
+/// without waiting for each other.
 pub struct SqlGenerator<'c> {
     context: llama_cpp_2::context::LlamaContext<'c>,
-    dialect: PostgreSqlDialect,
+    dialect: SqlDialect,
+    chat_format: ChatFormat,
+    sampling: Sampling,
+    slots: Vec<Slot>,
+    next_ticket: Ticket,
+    /// Requests that finished outside of a call to step
+    ready: Vec<(Ticket, Result<Generation, NaturalError>)>,
+    /// Directory evaluated prompt prefixes are persisted to
+    state_dir: Option<PathBuf>,
+}
+
+#[derive(Default)]
+struct Slot {
+    /// Tokens held in the kv cache of this sequence, in order of their position
+    cached: Vec<LlamaToken>,
+    request: Option<Request>,
+}
+
+struct Request {
+    ticket: Ticket,
+    /// Prompt tokens not yet evaluated
+    pending: Vec<LlamaToken>,
+    /// Sampled token to be decoded in the next step
+    next: Option<LlamaToken>,
+    /// Batch index holding the logits of this sequence after the current step
+    logits: Option<i32>,
+    sampler: LlamaSampler,
+    decoder: encoding_rs::Decoder,
+    output: String,
+    /// Sequences ending generation once they appear in the output
+    stop: Vec<String>,
+    task: Task,
+    dialect: SqlDialect,
+    generated: usize,
+    max_tokens: usize,
+    /// Sum of the log probabilities of the generated tokens
+    log_probability: f64,
+    prompt_tokens: usize,
+    cached_tokens: usize,
+    started: i64,
+    /// When the prompt was evaluated and sampling began
+    evaluated: Option<i64>,
+    /// When the request times out, along with its timeout
+    deadline: Option<(Instant, Duration)>,
 }
 
 impl<'c> SqlGenerator<'c> {
     pub fn new(context: LlamaContext<'c>) -> Result<Self> {
         Ok(Self {
             context,
-            dialect: PostgreSqlDialect {},
+            dialect: SqlDialect::default(),
+            chat_format: ChatFormat::Auto,
+            sampling: Sampling::default(),
+            slots: vec![Slot::default()],
+            next_ticket: 0,
+            ready: vec![],
+            state_dir: None,
         })
     }
 
-    pub fn generate(&mut self, query: &str, schema: &str) -> Result<Statement> {
-        let prompt = PROMPT
-            .replace("{QUESTION}", query)
-            .replace("{SCHEMA}", schema);
+    /// Generate sql of `dialect` instead of postgres. Prompts should carry its
+    /// [`SqlDialect::notes`], outputs are parsed and validated against it.
+    pub fn with_dialect(mut self, dialect: SqlDialect) -> Self {
+        self.dialect = dialect;
+        self
+    }
+
+    /// Dialect of the sql this generator produces
+    pub fn dialect(&self) -> SqlDialect {
+        self.dialect
+    }
+
+    /// Generate sql of `dialect` for requests submitted from now on
+    pub fn set_dialect(&mut self, dialect: SqlDialect) {
+        self.dialect = dialect;
+    }
+
+    /// Override the chat template of the model, e.g. for models shipping a broken one
+    pub fn with_chat_format(mut self, chat_format: ChatFormat) -> Self {
+        self.chat_format = chat_format;
+        self
+    }
+
+    /// Sample with `sampling` instead of always choosing the most likely token
+    pub fn with_sampling(mut self, sampling: Sampling) -> Self {
+        self.sampling = sampling;
+        self
+    }
+
+    /// Sample requests submitted from now on with `sampling`
+    pub fn set_sampling(&mut self, sampling: Sampling) {
+        self.sampling = sampling;
+    }
+
+    /// Serve up to `slots` requests concurrently. The context must be created with at least as
+    /// many sequences (`n_seq_max`).
+    pub fn with_slots(mut self, slots: usize) -> Self {
+        self.slots.resize_with(slots.max(1), Slot::default);
+        self
+    }
+
+    /// Persist the kv cache per prompt cache key to `dir`, so a fresh context can restore the
+    /// instructions and schema instead of decoding them again.
+    ///
+    /// States are restored without further checks, `dir` must be specific to the model file,
+    /// context size and slots of this generator.
+    pub fn with_state_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.state_dir = Some(dir.into());
+        self
+    }
+
+    /// Generate sql for a single prompt, the generator must not serve other requests.
+    ///
+    /// `interrupted` is checked before every decode step, also while the prompt is evaluated,
+    /// generation is cancelled once it returns true.
+    pub fn generate(
+        &mut self,
+        prompt: &Prompt,
+        timeout: Option<Duration>,
+        mut interrupted: impl FnMut() -> bool,
+    ) -> Result<Generation, NaturalError> {
+        if !self.is_idle() {
+            return Err(NaturalError::internal(
+                "generator is busy serving other requests",
+            ));
+        }
+
+        let ticket = self.submit(prompt, timeout, &mut interrupted)?;
+
+        loop {
+            if interrupted() {
+                self.cancel(ticket);
+                return Err(NaturalError::Cancelled);
+            }
+
+            for (finished, result) in self.step() {
+                if finished == ticket {
+                    return result;
+                }
+            }
+        }
+    }
+
+    /// Whether another request can be submitted
+    pub fn has_capacity(&self) -> bool {
+        self.slots.iter().any(|slot| slot.request.is_none())
+    }
+
+    /// Whether no request is in flight
+    pub fn is_idle(&self) -> bool {
+        self.slots.iter().all(|slot| slot.request.is_none())
+    }
+
+    /// Start generating sql for a prompt, the result is returned by [`SqlGenerator::step`].
+    /// Requests still generating after `timeout` fail with [`NaturalError::Timeout`].
+    ///
+    /// The request is placed in the free slot sharing the longest prefix with the prompt, so
+    /// only the part of the prompt that differs has to be evaluated. A prompt evaluated ahead
+    /// of time to persist its state is cancelled once `interrupted` returns true.
+    pub fn submit(
+        &mut self,
+        prompt: &Prompt,
+        timeout: Option<Duration>,
+        interrupted: impl FnMut() -> bool,
+    ) -> Result<Ticket, NaturalError> {
+        let state = self
+            .state_dir
+            .as_ref()
+            .zip(prompt.cache_key.as_ref())
+            .map(|(dir, key)| dir.join(format!("{key}.state")));
+
+        let (prompt, add_bos) = chat::format(self.context.model, prompt, self.chat_format)?;
+
+        let tokens = self
+            .context
+            .model
+            .str_to_token(&prompt, add_bos)
+            .map_err(NaturalError::internal)?;
+
+        if tokens.is_empty() {
+            return Err(NaturalError::internal("prompt must not be empty"));
+        }
+
+        // The prompt and at least one generated token have to fit into a slot
+        if tokens.len() >= self.capacity() {
+            return Err(NaturalError::ContextOverflow {
+                tokens: tokens.len(),
+                capacity: self.capacity(),
+            });
+        }
+
+        debug!(target: crate::log::PROMPTS, ticket = self.next_ticket, "prompt:\n{prompt}");
 
-        let tokens = self.context.model.str_to_token(&prompt, AddBos::Always)?;
+        if self.slots.iter().all(|slot| slot.cached.is_empty()) {
+            if let Some(path) = state.as_ref().filter(|path| path.exists()) {
+                self.restore(path);
+            }
+        }
+
+        let (seq, common) = free_slot(&self.slots, &tokens)
+            .ok_or_else(|| NaturalError::internal("no free slot to serve the request"))?;
+
+        // The last token is always decoded again to obtain its logits
+        let common = common.min(tokens.len() - 1);
+
+        self.context
+            .clear_kv_cache_seq(Some(seq as u32), Some(common as u32), None)
+            .map_err(NaturalError::internal)?;
+
+        let ticket = self.next_ticket;
+        self.next_ticket += 1;
 
-        let mut batch = LlamaBatch::new(512, 1);
+        debug!(
+            ticket,
+            seq,
+            prompt_tokens = tokens.len(),
+            cached_tokens = common,
+            "submitted request"
+        );
 
-        let last_index: i32 = (tokens.len() - 1) as i32;
-        for (i, token) in (0_i32..).zip(tokens.into_iter()) {
-            let is_last = i == last_index;
-            batch.add(token, i, &[0], is_last)?;
+        let slot = &mut self.slots[seq];
+        slot.cached.truncate(common);
+        slot.request = Some(Request {
+            ticket,
+            pending: tokens[common..].to_vec(),
+            next: None,
+            logits: None,
+            sampler: self.sampling.sampler(),
+            decoder: encoding_rs::UTF_8.new_decoder(),
+            output: String::new(),
+            stop: prompt.stop.clone(),
+            task: prompt.task,
+            dialect: self.dialect,
+            generated: 0,
+            max_tokens: self.sampling.max_tokens,
+            log_probability: 0.0,
+            prompt_tokens: tokens.len(),
+            cached_tokens: common,
+            started: ggml_time_us(),
+            evaluated: None,
+            deadline: timeout.map(|timeout| (Instant::now() + timeout, timeout)),
+        });
+
+        // Only a context holding nothing but this prompt can be saved as a whole, and only from
+        // the first slot as that is where it is restored to
+        if let Some(path) = state.filter(|path| !path.exists()) {
+            if seq == 0 && self.is_only(seq) && self.evaluate_prompt(seq, interrupted)? {
+                self.save(&path)?;
+            }
         }
 
-        self.context.decode(&mut batch)?;
+        Ok(ticket)
+    }
+
+    /// Stop a request, it is not returned by [`SqlGenerator::step`]. Returns whether the
+    /// request was still in flight.
+    pub fn cancel(&mut self, ticket: Ticket) -> bool {
+        self.ready.retain(|(finished, _)| *finished != ticket);
+
+        let Some(slot) = self
+            .slots
+            .iter_mut()
+            .find(|slot| slot.request.as_ref().is_some_and(|r| r.ticket == ticket))
+        else {
+            return false;
+        };
+
+        // Only decoded tokens are cached, so the slot stays valid for prefix reuse
+        slot.request = None;
 
-        let mut output = String::new();
+        debug!(ticket, "cancelled request");
 
-        let n_len = 1024;
-        let mut n_cur = batch.n_tokens();
+        true
+    }
+
+    /// Decode one batch and return the requests that finished with it. Failures only affect
+    /// the requests concerned, they are returned along with the others.
+    pub fn step(&mut self) -> Vec<(Ticket, Result<Generation, NaturalError>)> {
+        self.expire();
+
+        let n_batch = self.context.n_batch() as usize;
+        let mut batch = LlamaBatch::new(n_batch, 1);
+
+        // Generating requests go first, they only need a single token each. Those not fitting
+        // into the batch keep their token for the next step.
+        for (seq, slot) in self.slots.iter_mut().enumerate() {
+            let Some(request) = slot.request.as_mut() else {
+                continue;
+            };
+
+            request.logits = None;
+
+            if batch.n_tokens() as usize >= n_batch {
+                continue;
+            }
+
+            let Some(token) = request.next.take() else {
+                continue;
+            };
+
+            request.logits = Some(batch.n_tokens());
 
-        let t_main_start = ggml_time_us();
+            match batch.add(token, slot.cached.len() as i32, &[seq as i32], true) {
+                Ok(()) => slot.cached.push(token),
+                Err(e) => fail(&mut self.ready, slot, e),
+            }
+        }
 
-        // The `Decoder`
-        let mut decoder = encoding_rs::UTF_8.new_decoder();
+        // Fill the remainder of the batch with prompt chunks
+        for (seq, slot) in self.slots.iter_mut().enumerate() {
+            let Some(request) = slot.request.as_mut() else {
+                continue;
+            };
 
-        let mut sampler =
-            LlamaSampler::chain_simple([LlamaSampler::dist(1234), LlamaSampler::greedy()]);
+            let room = n_batch.saturating_sub(batch.n_tokens() as usize);
+            let take = request.pending.len().min(room);
+            let completes = take == request.pending.len();
+            let mut failed = None;
 
-        while n_cur <= n_len {
-            // sample the next token
-            {
-                let token = sampler.sample(&self.context, batch.n_tokens() - 1);
+            for (i, token) in request.pending.drain(..take).enumerate() {
+                let logits = completes && i + 1 == take;
 
-                sampler.accept(token);
+                if logits {
+                    request.logits = Some(batch.n_tokens());
+                }
 
-                // is it an end of stream?
-                if self.context.model.is_eog_token(token) {
-                    eprintln!("DONE");
+                if let Err(e) = batch.add(token, slot.cached.len() as i32, &[seq as i32], logits) {
+                    failed = Some(e);
                     break;
                 }
 
-                let output_bytes = self
-                    .context
-                    .model
-                    .token_to_bytes(token, Special::Tokenize)?;
+                slot.cached.push(token);
+            }
+
+            if let Some(e) = failed {
+                fail(&mut self.ready, slot, e);
+            }
+        }
+
+        let mut finished = std::mem::take(&mut self.ready);
+
+        if batch.n_tokens() == 0 {
+            return finished;
+        }
+
+        if let Err(e) = self.context.decode(&mut batch) {
+            error!("failed to decode batch of {} tokens: {e}", batch.n_tokens());
+
+            // The kv cache no longer matches any slot, fail everything in flight
+            let tickets = self
+                .slots
+                .iter()
+                .filter_map(|slot| slot.request.as_ref().map(|r| r.ticket))
+                .collect::<Vec<_>>();
 
-                let mut decoded = String::with_capacity(64);
+            self.reset();
 
-                let _decode_result = decoder.decode_to_string(&output_bytes, &mut decoded, false);
+            let error = NaturalError::Decode {
+                message: e.to_string(),
+            };
+
+            finished.extend(
+                tickets
+                    .into_iter()
+                    .map(|ticket| (ticket, Err(error.clone()))),
+            );
+
+            return finished;
+        }
 
-                output.push_str(&decoded);
+        for seq in 0..self.slots.len() {
+            let Some(logits) = self.slots[seq].request.as_ref().and_then(|r| r.logits) else {
+                continue;
+            };
 
-                batch.clear();
-                batch.add(token, n_cur, &[0], true)?;
+            match self.sample(seq, logits) {
+                Ok(true) => {
+                    let request = self.slots[seq].request.take().unwrap();
+                    finished.push((request.ticket, self.finish(request)));
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    let request = self.slots[seq].request.take().unwrap();
+                    finished.push((request.ticket, Err(e)));
+                }
             }
+        }
+
+        finished
+    }
+
+    /// Sample the next token of a sequence, returns whether the request is complete
+    fn sample(&mut self, seq: usize, logits: i32) -> Result<bool, NaturalError> {
+        let model = self.context.model;
+        let capacity = self.capacity();
+        let slot = &mut self.slots[seq];
+        let request = slot.request.as_mut().unwrap();
 
-            n_cur += 1;
+        request.evaluated.get_or_insert_with(ggml_time_us);
 
-            self.context
-                .decode(&mut batch)
-                .with_context(|| "failed to eval")?;
+        let token = request.sampler.sample(&self.context, logits);
+
+        request.sampler.accept(token);
+
+        // is it an end of stream?
+        if model.is_eog_token(token) || request.generated >= request.max_tokens {
+            return Ok(true);
+        }
+
+        if slot.cached.len() + 1 >= capacity {
+            return Err(NaturalError::ContextOverflow {
+                tokens: slot.cached.len() + 1,
+                capacity,
+            });
         }
 
-        eprintln!("\n");
+        let output_bytes = model
+            .token_to_bytes(token, Special::Tokenize)
+            .map_err(NaturalError::internal)?;
+
+        let mut decoded = String::with_capacity(64);
+
+        let _decode_result = request
+            .decoder
+            .decode_to_string(&output_bytes, &mut decoded, false);
 
-        let t_main_end = ggml_time_us();
+        let appended = request.output.len();
 
-        let duration = Duration::from_micros((t_main_end - t_main_start) as u64);
+        request.output.push_str(&decoded);
+        request.generated += 1;
+        request.log_probability +=
+            log_probability(self.context.get_logits_ith(logits), token.0 as usize);
 
-        dbg!(duration);
+        if let Some(end) = stop_at(&request.output, &request.stop, appended) {
+            request.output.truncate(end);
 
-        let sql = self.extract_sql(&output)?;
+            if request.task == Task::Sql {
+                close_sql_tag(&mut request.output);
+            }
+
+            return Ok(true);
+        }
 
-        let parsed = Parser::parse_sql(&self.dialect, &sql).context("Invalid SQL syntax")?;
+        request.next = Some(token);
 
-        ensure!(
-            parsed.len() == 1,
-            "expected llm to output exactly one sql query"
+        Ok(false)
+    }
+
+    fn finish(&self, request: Request) -> Result<Generation, NaturalError> {
+        let finished = ggml_time_us();
+        let evaluated = request.evaluated.unwrap_or(finished);
+
+        let timings = Timings {
+            queue_ms: 0.0,
+            prompt_ms: (evaluated - request.started) as f64 / 1000.0,
+            generation_ms: (finished - evaluated) as f64 / 1000.0,
+        };
+
+        debug!(
+            ticket = request.ticket,
+            generated_tokens = request.generated,
+            prompt_ms = timings.prompt_ms,
+            generation_ms = timings.generation_ms,
+            "finished request"
         );
 
-        Ok(parsed[0].clone())
-    }
-
-    fn extract_sql(&self, output: &str) -> eyre::Result<String> {
-        output
-            .lines()
-            .filter(|line| !line.starts_with("```") && !line.trim().is_empty())
-            .collect::<Vec<&str>>()
-            .join("\n")
-            .trim()
-            .strip_prefix("<sql>")
-            .wrap_err_with(|| {
-                eyre!("Unexpected model output: Missing opening sql tag: {output:#?}")
-            })?
-            .strip_suffix("</sql>")
-            .wrap_err_with(|| {
-                eyre!("Unexpected model output: Missing closing sql tag: {output:#?}")
-            })
-            .map(|o| o.to_string())
+        debug!(target: crate::log::PROMPTS, ticket = request.ticket, "output:\n{}", request.output);
+
+        let outcome = match request.task {
+            Task::Sql => match extract::clarification(&request.output) {
+                Some(clarification) => Outcome::Clarification(clarification),
+                None => sql(&request.output, request.dialect)?,
+            },
+            Task::Explain => {
+                Outcome::Explanation(extract::explanation(&request.output).ok_or_else(|| {
+                    NaturalError::MissingSqlTag {
+                        tag: "<explanation>".to_string(),
+                        output: request.output.clone(),
+                    }
+                })?)
+            }
+        };
+
+        Ok(Generation {
+            outcome,
+            confidence: (request.log_probability / request.generated.max(1) as f64).exp(),
+            output: request.output,
+            prompt_tokens: request.prompt_tokens,
+            cached_tokens: request.cached_tokens,
+            generated_tokens: request.generated,
+            timings,
+        })
+    }
+
+    /// Fail requests that ran past their deadline
+    fn expire(&mut self) {
+        let now = Instant::now();
+
+        for slot in &mut self.slots {
+            let Some((_, timeout)) = slot
+                .request
+                .as_ref()
+                .and_then(|r| r.deadline)
+                .filter(|(deadline, _)| *deadline <= now)
+            else {
+                continue;
+            };
+
+            let request = slot.request.take().unwrap();
+
+            debug!(ticket = request.ticket, "request timed out");
+
+            self.ready.push((
+                request.ticket,
+                Err(NaturalError::Timeout {
+                    ms: timeout.as_millis() as u64,
+                }),
+            ));
+        }
+    }
+
+    /// Whether `seq` is the only sequence holding anything in the kv cache
+    fn is_only(&self, seq: usize) -> bool {
+        self.slots
+            .iter()
+            .enumerate()
+            .all(|(i, slot)| i == seq || (slot.cached.is_empty() && slot.request.is_none()))
+    }
+
+    /// Evaluate the whole pending prompt of a sequence ahead of the next step, checking for
+    /// interruptions and the deadline between batches. Returns whether the prompt was evaluated
+    /// completely, it is not if the request timed out.
+    fn evaluate_prompt(
+        &mut self,
+        seq: usize,
+        mut interrupted: impl FnMut() -> bool,
+    ) -> Result<bool, NaturalError> {
+        loop {
+            let Some(request) = self.slots[seq].request.as_ref() else {
+                return Ok(false);
+            };
+
+            if request.pending.is_empty() {
+                return Ok(true);
+            }
+
+            if interrupted() {
+                self.cancel(request.ticket);
+                return Err(NaturalError::Cancelled);
+            }
+
+            // Expires the request once past its deadline
+            let finished = self.step();
+            self.ready.extend(finished);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.context.clear_kv_cache();
+
+        for slot in &mut self.slots {
+            *slot = Slot::default();
+        }
+    }
+
+    /// Load a saved kv cache into the first slot, an incompatible file just means starting cold
+    fn restore(&mut self, path: &Path) {
+        let capacity = self.context.n_ctx() as usize;
+
+        match self.context.load_session_file(path, capacity) {
+            Ok(tokens) => {
+                debug!("restored {} tokens from {path:?}", tokens.len());
+                self.slots[0].cached = tokens;
+            }
+            Err(e) => {
+                warn!("failed to restore prompt state from {path:?}: {e}");
+                self.reset();
+            }
+        }
+    }
+
+    /// Save the kv cache of the first slot, see [`SqlGenerator::restore`]
+    fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        // Write to a temporary file first, concurrent readers must never see partial state
+        let tmp = path.with_extension("tmp");
+
+        self.context
+            .save_session_file(&tmp, &self.slots[0].cached)
+            .with_context(|| format!("failed to save prompt state to {tmp:?}"))?;
+
+        std::fs::rename(&tmp, path)?;
+
+        debug!("saved {} tokens to {path:?}", self.slots[0].cached.len());
+
+        Ok(())
+    }
+
+    /// Tokens a single slot may hold
+    fn capacity(&self) -> usize {
+        self.context.n_ctx() as usize / self.slots.len()
+    }
+}
+
+/// Extract and validate the sql of an output
+fn sql(output: &str, dialect: SqlDialect) -> Result<Outcome, NaturalError> {
+    let sql =
+        extract::extract(output, dialect.parser()).ok_or_else(|| NaturalError::MissingSqlTag {
+            tag: "<sql>".to_string(),
+            output: output.to_string(),
+        })?;
+
+    let parsed = Parser::parse_sql(dialect.parser(), &sql).map_err(|e| NaturalError::Parse {
+        message: e.to_string(),
+        output: output.to_string(),
+    })?;
+
+    let [statement] = parsed.as_slice() else {
+        return Err(NaturalError::Validation {
+            message: format!("expected exactly one statement, got {}", parsed.len()),
+            output: output.to_string(),
+        });
+    };
+
+    Ok(Outcome::Sql {
+        sql: statement.to_string(),
+        explanation: extract::explanation(output),
+        tables: inspect::tables(statement),
+    })
+}
+
+/// Fail the request of a slot whose tokens could not be added to the batch
+fn fail(
+    ready: &mut Vec<(Ticket, Result<Generation, NaturalError>)>,
+    slot: &mut Slot,
+    error: impl std::fmt::Display,
+) {
+    if let Some(request) = slot.request.take() {
+        error!(
+            ticket = request.ticket,
+            "failed to add tokens to the batch: {error}"
+        );
+        ready.push((request.ticket, Err(NaturalError::internal(error))));
+    }
+}
+
+/// The free slot sharing the longest prefix with `tokens` along with the length of that prefix.
+/// Ties go to the first slot, which is where restored states are placed.
+fn free_slot(slots: &[Slot], tokens: &[LlamaToken]) -> Option<(usize, usize)> {
+    slots
+        .iter()
+        .enumerate()
+        .filter(|(_, slot)| slot.request.is_none())
+        .map(|(seq, slot)| (seq, common_prefix(&slot.cached, tokens)))
+        .min_by_key(|&(seq, common)| (Reverse(common), seq))
+}
+
+fn common_prefix(a: &[LlamaToken], b: &[LlamaToken]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+/// Natural logarithm of the probability the model assigned to `token`
+fn log_probability(logits: &[f32], token: usize) -> f64 {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max) as f64;
+    let sum = logits
+        .iter()
+        .map(|logit| (*logit as f64 - max).exp())
+        .sum::<f64>();
+
+    logits[token] as f64 - max - sum.ln()
+}
+
+/// End of the first stop sequence completed by the text appended to `output` at `appended`
+fn stop_at(output: &str, stop: &[String], appended: usize) -> Option<usize> {
+    stop.iter()
+        .filter(|stop| !stop.is_empty())
+        .filter_map(|stop| {
+            // The sequence may have started in text appended before
+            let mut from = appended.saturating_sub(stop.len() - 1);
+
+            while !output.is_char_boundary(from) {
+                from -= 1;
+            }
+
+            output[from..]
+                .find(stop.as_str())
+                .map(|i| from + i + stop.len())
+        })
+        .min()
+}
+
+/// Close an `<sql>` tag left open by stopping on the end of a statement
+fn close_sql_tag(output: &mut String) {
+    let open = output.rfind("<sql>");
+    let close = output.rfind("</sql>");
+
+    if open.is_some_and(|open| close.is_none_or(|close| close < open)) {
+        if !output.ends_with('\n') {
+            output.push('\n');
+        }
+
+        output.push_str("</sql>");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stop() -> Vec<String> {
+        crate::prompt::DEFAULT_STOP
+            .iter()
+            .map(|s| s.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn stops_on_sequences_spanning_tokens() {
+        let output = "<sql>SELECT 1</s";
+        assert_eq!(stop_at(output, &stop(), 10), None);
+
+        let output = "<sql>SELECT 1</sql>\nThis query";
+        assert_eq!(stop_at(output, &stop(), 16), Some(19));
+    }
+
+    #[test]
+    fn closes_tags_left_open_by_statement_ends() {
+        let mut output = "<sql>\nSELECT 1;\n".to_string();
+        let end = stop_at(&output, &stop(), 14).unwrap();
+
+        output.truncate(end);
+        close_sql_tag(&mut output);
+        assert_eq!(output, "<sql>\nSELECT 1;\n</sql>");
+
+        let mut output = "<sql>SELECT 1</sql>".to_string();
+        close_sql_tag(&mut output);
+        assert_eq!(output, "<sql>SELECT 1</sql>");
+    }
+
+    #[test]
+    fn prefers_the_first_slot_on_ties() {
+        let tokens = |ids: &[i32]| ids.iter().map(|&id| LlamaToken(id)).collect::<Vec<_>>();
+
+        let mut slots = vec![Slot::default(), Slot::default()];
+        assert_eq!(free_slot(&slots, &tokens(&[1, 2, 3])), Some((0, 0)));
+
+        // State restored into the first slot
+        slots[0].cached = tokens(&[1, 2, 3]);
+        assert_eq!(free_slot(&slots, &tokens(&[1, 2, 4])), Some((0, 2)));
+        assert_eq!(free_slot(&slots, &tokens(&[5, 6])), Some((0, 0)));
+
+        slots[1].cached = tokens(&[5]);
+        assert_eq!(free_slot(&slots, &tokens(&[5, 6])), Some((1, 1)));
+    }
+
+    #[test]
+    fn log_probabilities_of_logits() {
+        let logits = [1000.0, 1000.0, 0.0];
+
+        assert!((log_probability(&logits, 0) - 0.5f64.ln()).abs() < 1e-9);
+        assert!(log_probability(&logits, 2) < -900.0);
     }
 }
@@ -0,0 +1,320 @@
+//! Metadata of GGUF model files, read from their header without loading the model.
+//!
+//! See <https://github.com/ggml-org/ggml/blob/master/docs/gguf.md> for the format.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+use eyre::{bail, Result};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+const MAGIC: &[u8; 4] = b"GGUF";
+
+/// Strings beyond this length are taken for a corrupt file rather than allocated
+const MAX_STRING: u64 = 16 * 1024 * 1024;
+
+/// Dimensions a tensor may have at most
+const MAX_DIMS: u32 = 4;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ModelInfo {
+    /// e.g. `llama` or `qwen2`
+    pub architecture: Option<String>,
+    pub parameters: u64,
+    /// Quantisation of the bulk of the tensors, e.g. `Q4_K_M`
+    pub quantization: Option<String>,
+    /// Context length in tokens the model was trained with
+    pub context_length: Option<u64>,
+    pub vocab_size: Option<u64>,
+    /// Jinja chat template embedded in the model
+    pub chat_template: Option<String>,
+}
+
+enum Value {
+    Int(u64),
+    String(String),
+    /// Length of an array, its elements are skipped
+    Array(u64),
+    /// Floats, booleans and negative integers, none of which are looked up
+    Other,
+}
+
+/// Key value pairs of the header, in file order
+struct Metadata(Vec<(String, Value)>);
+
+impl ModelInfo {
+    pub fn read(path: &Path) -> Result<Self> {
+        Self::parse(&mut BufReader::new(File::open(path)?))
+    }
+
+    fn parse(reader: &mut impl Read) -> Result<Self> {
+        if &bytes::<4>(reader)? != MAGIC {
+            bail!("Not a GGUF file");
+        }
+
+        // Version 1 used 32 bit counts and lengths
+        let version = read_u32(reader)?;
+        if version < 2 {
+            bail!("Unsupported GGUF version {version}");
+        }
+
+        let tensors = read_u64(reader)?;
+        let entries = read_u64(reader)?;
+
+        let mut metadata = Metadata(vec![]);
+
+        for _ in 0..entries {
+            let key = read_string(reader)?;
+            let kind = read_u32(reader)?;
+            metadata.0.push((key, read_value(reader, kind)?));
+        }
+
+        let mut parameters = 0u64;
+
+        for _ in 0..tensors {
+            let _name = read_string(reader)?;
+
+            let dims = read_u32(reader)?;
+            if dims > MAX_DIMS {
+                bail!("Invalid GGUF tensor with {dims} dimensions");
+            }
+
+            let mut elements = 1u64;
+            for _ in 0..dims {
+                elements = elements.saturating_mul(read_u64(reader)?);
+            }
+
+            let _kind = read_u32(reader)?;
+            let _offset = read_u64(reader)?;
+
+            parameters = parameters.saturating_add(elements);
+        }
+
+        let architecture = metadata.string("general.architecture");
+        let architecture_int = |key: &str| {
+            architecture
+                .as_ref()
+                .and_then(|architecture| metadata.int(&format!("{architecture}.{key}")))
+        };
+
+        Ok(Self {
+            parameters,
+            quantization: metadata.int("general.file_type").map(file_type),
+            context_length: architecture_int("context_length"),
+            vocab_size: metadata
+                .len("tokenizer.ggml.tokens")
+                .or_else(|| architecture_int("vocab_size")),
+            chat_template: metadata.string("tokenizer.chat_template"),
+            architecture,
+        })
+    }
+}
+
+impl Metadata {
+    fn get(&self, key: &str) -> Option<&Value> {
+        self.0
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value)
+    }
+
+    fn string(&self, key: &str) -> Option<String> {
+        match self.get(key) {
+            Some(Value::String(s)) => Some(s.clone()),
+            _ => None,
+        }
+    }
+
+    fn int(&self, key: &str) -> Option<u64> {
+        match self.get(key) {
+            Some(Value::Int(n)) => Some(*n),
+            _ => None,
+        }
+    }
+
+    fn len(&self, key: &str) -> Option<u64> {
+        match self.get(key) {
+            Some(Value::Array(len)) => Some(*len),
+            _ => None,
+        }
+    }
+}
+
+/// Hex encoded sha256 of a file
+pub fn sha256(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Name of a `general.file_type`, as llama.cpp calls it
+fn file_type(file_type: u64) -> String {
+    let name = match file_type {
+        0 => "F32",
+        1 => "F16",
+        2 => "Q4_0",
+        3 => "Q4_1",
+        7 => "Q8_0",
+        8 => "Q5_0",
+        9 => "Q5_1",
+        10 => "Q2_K",
+        11 => "Q3_K_S",
+        12 => "Q3_K_M",
+        13 => "Q3_K_L",
+        14 => "Q4_K_S",
+        15 => "Q4_K_M",
+        16 => "Q5_K_S",
+        17 => "Q5_K_M",
+        18 => "Q6_K",
+        19 => "IQ2_XXS",
+        20 => "IQ2_XS",
+        21 => "Q2_K_S",
+        22 => "IQ3_XS",
+        23 => "IQ3_XXS",
+        24 => "IQ1_S",
+        25 => "IQ4_NL",
+        26 => "IQ3_S",
+        27 => "IQ3_M",
+        28 => "IQ2_S",
+        29 => "IQ2_M",
+        30 => "IQ4_XS",
+        31 => "IQ1_M",
+        32 => "BF16",
+        36 => "TQ1_0",
+        37 => "TQ2_0",
+        other => return format!("unknown ({other})"),
+    };
+
+    name.to_string()
+}
+
+fn read_value(reader: &mut impl Read, kind: u32) -> Result<Value> {
+    let signed = |n: i64| u64::try_from(n).map_or(Value::Other, Value::Int);
+
+    Ok(match kind {
+        0 => Value::Int(u8::from_le_bytes(bytes(reader)?).into()),
+        1 => signed(i8::from_le_bytes(bytes(reader)?).into()),
+        2 => Value::Int(u16::from_le_bytes(bytes(reader)?).into()),
+        3 => signed(i16::from_le_bytes(bytes(reader)?).into()),
+        4 => Value::Int(read_u32(reader)?.into()),
+        5 => signed(i32::from_le_bytes(bytes(reader)?).into()),
+        6 => bytes::<4>(reader).map(|_| Value::Other)?,
+        7 => bytes::<1>(reader).map(|_| Value::Other)?,
+        8 => Value::String(read_string(reader)?),
+        9 => {
+            let kind = read_u32(reader)?;
+            let len = read_u64(reader)?;
+
+            for _ in 0..len {
+                read_value(reader, kind)?;
+            }
+
+            Value::Array(len)
+        }
+        10 => Value::Int(read_u64(reader)?),
+        11 => signed(i64::from_le_bytes(bytes(reader)?)),
+        12 => bytes::<8>(reader).map(|_| Value::Other)?,
+        kind => bail!("Invalid GGUF value type {kind}"),
+    })
+}
+
+fn read_string(reader: &mut impl Read) -> Result<String> {
+    let len = read_u64(reader)?;
+    if len > MAX_STRING {
+        bail!("Invalid GGUF string of {len} bytes");
+    }
+
+    let mut buf = vec![0; len as usize];
+    reader.read_exact(&mut buf)?;
+
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32> {
+    Ok(u32::from_le_bytes(bytes(reader)?))
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64> {
+    Ok(u64::from_le_bytes(bytes(reader)?))
+}
+
+fn bytes<const N: usize>(reader: &mut impl Read) -> Result<[u8; N]> {
+    let mut buf = [0; N];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(out: &mut Vec<u8>, s: &str) {
+        out.extend((s.len() as u64).to_le_bytes());
+        out.extend(s.as_bytes());
+    }
+
+    fn entry(out: &mut Vec<u8>, key: &str, kind: u32, value: &[u8]) {
+        string(out, key);
+        out.extend(kind.to_le_bytes());
+        out.extend(value);
+    }
+
+    fn tensor(out: &mut Vec<u8>, name: &str, dims: &[u64]) {
+        string(out, name);
+        out.extend((dims.len() as u32).to_le_bytes());
+        dims.iter().for_each(|dim| out.extend(dim.to_le_bytes()));
+        out.extend(12u32.to_le_bytes());
+        out.extend(0u64.to_le_bytes());
+    }
+
+    #[test]
+    fn reads_the_header() {
+        let mut file = b"GGUF".to_vec();
+        file.extend(3u32.to_le_bytes());
+        file.extend(2u64.to_le_bytes());
+        file.extend(6u64.to_le_bytes());
+
+        let mut architecture = vec![];
+        string(&mut architecture, "llama");
+        entry(&mut file, "general.architecture", 8, &architecture);
+        entry(&mut file, "general.file_type", 4, &15u32.to_le_bytes());
+        entry(&mut file, "llama.context_length", 4, &8192u32.to_le_bytes());
+        entry(&mut file, "llama.rope.freq_base", 6, &1e4f32.to_le_bytes());
+
+        let mut tokens = 8u32.to_le_bytes().to_vec();
+        tokens.extend(3u64.to_le_bytes());
+        ["<s>", "</s>", "a"]
+            .iter()
+            .for_each(|t| string(&mut tokens, t));
+        entry(&mut file, "tokenizer.ggml.tokens", 9, &tokens);
+
+        let mut template = vec![];
+        string(&mut template, "{{ messages }}");
+        entry(&mut file, "tokenizer.chat_template", 8, &template);
+
+        tensor(&mut file, "token_embd.weight", &[4, 3]);
+        tensor(&mut file, "output_norm.weight", &[4]);
+
+        let info = ModelInfo::parse(&mut file.as_slice()).unwrap();
+
+        assert_eq!(
+            info,
+            ModelInfo {
+                architecture: Some("llama".to_string()),
+                parameters: 16,
+                quantization: Some("Q4_K_M".to_string()),
+                context_length: Some(8192),
+                vocab_size: Some(3),
+                chat_template: Some("{{ messages }}".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(ModelInfo::parse(&mut b"PK\x03\x04....".as_slice()).is_err());
+    }
+}
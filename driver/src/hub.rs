@@ -0,0 +1,154 @@
+//! Models of the Hugging Face hub, resolved from local directories without network access.
+//!
+//! A source is either a hub cache, as populated by `huggingface-cli download`, or a mirror laid
+//! out as `<repo>/<file>`, e.g. `TheBloke/Mistral-7B-GGUF/mistral-7b.Q4_K_M.gguf`. Installing a
+//! model hard links it into a models directory, or copies it if that is on another file system.
+
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use eyre::{bail, Result, WrapErr};
+use hf_hub::Cache;
+
+/// A model file found in a source
+#[derive(Clone, Debug, PartialEq)]
+pub struct Resolved {
+    pub path: PathBuf,
+    /// Hex encoded sha256 of the file, if the source records it. Hub caches name the blobs of
+    /// large files by their sha256.
+    pub sha256: Option<String>,
+}
+
+/// The hub cache of the environment, `$HF_HOME/hub` or `~/.cache/huggingface/hub`
+pub fn default_cache() -> PathBuf {
+    Cache::default().path().clone()
+}
+
+/// Find `file` of `repo` in the first source holding it
+pub fn resolve(repo: &str, file: &str, sources: &[PathBuf]) -> Result<Resolved> {
+    if !is_relative(repo) || !is_relative(file) {
+        bail!("Invalid model {repo}/{file}, repositories and files must be relative paths");
+    }
+
+    for source in sources {
+        if let Some(path) = Cache::new(source.clone()).model(repo.to_string()).get(file) {
+            // Snapshots link to the blobs of the cache
+            let path = fs::canonicalize(path)?;
+
+            let sha256 = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .filter(|name| name.len() == 64 && name.bytes().all(|b| b.is_ascii_hexdigit()))
+                .map(str::to_ascii_lowercase);
+
+            return Ok(Resolved { path, sha256 });
+        }
+
+        let path = source.join(repo).join(file);
+
+        if path.is_file() {
+            return Ok(Resolved {
+                path: fs::canonicalize(path)?,
+                sha256: None,
+            });
+        }
+    }
+
+    let sources = sources
+        .iter()
+        .map(|source| source.display().to_string())
+        .collect::<Vec<_>>();
+
+    bail!("{repo}/{file} is in none of {}", sources.join(", "))
+}
+
+/// Place a model below `dir` as `<repo>/<file>`, replacing a model installed before. Returns
+/// the path it was installed at.
+pub fn install(model: &Resolved, dir: &Path, repo: &str, file: &str) -> Result<PathBuf> {
+    let target = dir.join(repo).join(file);
+
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)
+            .wrap_err_with(|| format!("failed to create {}", parent.display()))?;
+    }
+
+    // Renamed once complete, so an interrupted copy is never taken for a model
+    let partial = target.with_extension("partial");
+    let _ = fs::remove_file(&partial);
+
+    if fs::hard_link(&model.path, &partial).is_err() {
+        fs::copy(&model.path, &partial)
+            .wrap_err_with(|| format!("failed to copy {}", model.path.display()))?;
+    }
+
+    fs::rename(&partial, &target)?;
+
+    Ok(target)
+}
+
+/// Name a model is registered under unless given one, the file name without extension
+pub fn model_name(file: &str) -> String {
+    Path::new(file)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| file.to_string())
+}
+
+/// Whether a path stays below the directory it is joined to
+fn is_relative(path: &str) -> bool {
+    !path.is_empty()
+        && Path::new(path)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA256: &str = "b2cdbde7a3c6fba9dcb79d7ccdfd5f4e3c9cf3ab35b4bc4a6b8ddcbd3fa5c6d1";
+
+    #[test]
+    fn resolves_and_installs() {
+        let root = std::env::temp_dir().join(format!("natural-hub-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+
+        // A hub cache holding one model, and a mirror holding another
+        let cache = root.join("cache");
+        let repo = cache.join("models--acme--tiny-GGUF");
+        fs::create_dir_all(repo.join("refs")).unwrap();
+        fs::create_dir_all(repo.join("blobs")).unwrap();
+        fs::create_dir_all(repo.join("snapshots/0123abcd")).unwrap();
+        fs::write(repo.join("refs/main"), "0123abcd").unwrap();
+        fs::write(repo.join("blobs").join(SHA256), "cached").unwrap();
+        std::os::unix::fs::symlink(
+            Path::new("../../blobs").join(SHA256),
+            repo.join("snapshots/0123abcd/tiny.gguf"),
+        )
+        .unwrap();
+
+        let mirror = root.join("mirror");
+        fs::create_dir_all(mirror.join("acme/small-GGUF")).unwrap();
+        fs::write(mirror.join("acme/small-GGUF/small.gguf"), "mirrored").unwrap();
+
+        let sources = [cache, mirror];
+
+        let cached = resolve("acme/tiny-GGUF", "tiny.gguf", &sources).unwrap();
+        assert_eq!(cached.sha256.as_deref(), Some(SHA256));
+
+        let mirrored = resolve("acme/small-GGUF", "small.gguf", &sources).unwrap();
+        assert_eq!(mirrored.sha256, None);
+
+        assert!(resolve("acme/tiny-GGUF", "missing.gguf", &sources).is_err());
+        assert!(resolve("acme/tiny-GGUF", "../../etc/passwd", &sources).is_err());
+
+        let models = root.join("models");
+        let installed = install(&mirrored, &models, "acme/small-GGUF", "small.gguf").unwrap();
+
+        assert_eq!(installed, models.join("acme/small-GGUF/small.gguf"));
+        assert_eq!(fs::read_to_string(&installed).unwrap(), "mirrored");
+        assert_eq!(model_name("small.gguf"), "small");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,102 @@
+//! Facts about generated statements, derived from their syntax tree.
+
+use std::ops::ControlFlow;
+
+use sqlparser::ast::{ObjectName, Query, Statement, Visit, Visitor};
+
+/// Tables a statement reads from or writes to, in order of appearance and without the names
+/// of common table expressions
+pub fn tables(statement: &Statement) -> Vec<String> {
+    let mut relations = Relations::default();
+    let _ = statement.visit(&mut relations);
+
+    let mut tables: Vec<String> = vec![];
+
+    for relation in relations.relations {
+        if !is_cte(&relations.ctes, &relation) && !tables.contains(&relation) {
+            tables.push(relation);
+        }
+    }
+
+    tables
+}
+
+/// Names of the common table expressions of a statement
+pub(crate) fn ctes(statement: &Statement) -> Vec<String> {
+    let mut relations = Relations::default();
+    let _ = statement.visit(&mut relations);
+
+    relations.ctes
+}
+
+/// Whether `name` refers to one of `ctes`, comparing names like postgres: unquoted names are
+/// case insensitive
+pub(crate) fn is_cte(ctes: &[String], name: &str) -> bool {
+    ctes.iter().any(|cte| fold(cte) == fold(name))
+}
+
+fn fold(name: &str) -> String {
+    match name
+        .strip_prefix('"')
+        .and_then(|name| name.strip_suffix('"'))
+    {
+        Some(quoted) => quoted.replace("\"\"", "\""),
+        None => name.to_lowercase(),
+    }
+}
+
+#[derive(Default)]
+struct Relations {
+    relations: Vec<String>,
+    ctes: Vec<String>,
+}
+
+impl Visitor for Relations {
+    type Break = ();
+
+    fn pre_visit_query(&mut self, query: &Query) -> ControlFlow<()> {
+        if let Some(with) = &query.with {
+            self.ctes
+                .extend(with.cte_tables.iter().map(|cte| cte.alias.name.to_string()));
+        }
+
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_relation(&mut self, relation: &ObjectName) -> ControlFlow<()> {
+        self.relations.push(relation.to_string());
+        ControlFlow::Continue(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlparser::dialect::PostgreSqlDialect;
+    use sqlparser::parser::Parser;
+
+    use super::*;
+
+    fn tables_of(sql: &str) -> Vec<String> {
+        let statements = Parser::parse_sql(&PostgreSqlDialect {}, sql).unwrap();
+        tables(&statements[0])
+    }
+
+    #[test]
+    fn lists_tables_once_without_ctes() {
+        assert_eq!(
+            tables_of(
+                "WITH recent AS (SELECT * FROM orders WHERE id > 10)
+                 SELECT u.name, count(*) FROM public.users u
+                 JOIN recent r ON r.user_id = u.id
+                 WHERE u.id IN (SELECT user_id FROM orders)
+                 GROUP BY u.name"
+            ),
+            ["orders", "public.users"]
+        );
+
+        assert_eq!(
+            tables_of("UPDATE users SET name = 'henry' WHERE id = 1"),
+            ["users"]
+        );
+    }
+}
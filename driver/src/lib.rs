@@ -1 +1,14 @@
+pub mod chat;
+pub mod dialect;
+pub mod error;
+pub mod extract;
 pub mod generator;
+pub mod gguf;
+pub mod hub;
+pub mod inspect;
+pub mod log;
+pub mod pool;
+pub mod prompt;
+pub mod protocol;
+pub mod rewrite;
+pub mod server;
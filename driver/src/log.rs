@@ -0,0 +1,57 @@
+//! Logging of the driver, built on `tracing`.
+//!
+//! The driver only emits events, the program embedding it decides where they end up: the
+//! extension forwards them to the postgres log, the command line to stderr.
+
+use std::str::FromStr;
+
+use eyre::{bail, eyre, Result};
+use llama_cpp_2::{send_logs_to_tracing, LogOptions};
+use tracing_subscriber::EnvFilter;
+
+/// Target of events carrying prompts and raw model output. These contain questions and schema
+/// details, so they are only logged when asked for explicitly.
+pub const PROMPTS: &str = "natural::prompts";
+
+/// Route the logs of llama.cpp through `tracing` instead of writing them to stderr
+pub fn capture_llama_logs() {
+    send_logs_to_tracing(LogOptions::default());
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            other => bail!("unknown log format {other:?}, expected text or json"),
+        }
+    }
+}
+
+/// Log to stderr, filtered by `RUST_LOG`. Prompts are logged with `RUST_LOG=natural::prompts=debug`.
+pub fn init_stderr(format: Format) -> Result<()> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("warn"));
+
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+
+    match format {
+        Format::Text => builder.try_init(),
+        Format::Json => builder.json().try_init(),
+    }
+    .map_err(|e| eyre!(e))?;
+
+    capture_llama_logs();
+
+    Ok(())
+}
@@ -0,0 +1,523 @@
+//! Models loaded on demand, each with its own [`SqlGenerator`].
+//!
+//! Requests name the model they are generated with, or use the default model of the pool. A
+//! model is loaded on its first request and kept afterwards. Loading a model beyond the memory
+//! budget first unloads the least recently used models that are not generating, requests for
+//! a model that does not fit yet wait until enough models are idle.
+
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use llama_cpp_2::context::params::LlamaContextParams;
+use llama_cpp_2::llama_backend::LlamaBackend;
+use llama_cpp_2::model::params::LlamaModelParams;
+use llama_cpp_2::model::LlamaModel;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::chat::ChatFormat;
+use crate::dialect::SqlDialect;
+use crate::error::NaturalError;
+use crate::generator::{Generation, Sampling, SqlGenerator, Ticket};
+use crate::gguf;
+use crate::prompt::{fingerprint, Prompt};
+
+/// Context size per concurrently served request, unless configured otherwise
+pub const CONTEXT_SIZE: u32 = 4096;
+
+/// A model and how to generate with it
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ModelSpec {
+    pub name: String,
+    /// GGUF model file
+    pub path: PathBuf,
+    /// Context size in tokens per concurrently served request
+    pub context_size: u32,
+    #[serde(default)]
+    pub chat_format: ChatFormat,
+    #[serde(default)]
+    pub sampling: Sampling,
+    /// Hex encoded sha256 the file must have, see [`ModelSpec::verify`]
+    #[serde(default)]
+    pub sha256: Option<String>,
+    /// The file as it was when its sha256 was verified by this process, see
+    /// [`ModelSpec::verify`]. Otherwise the pool hashes the file before loading it. Never
+    /// taken from a request, a client must not vouch for a file the server reads.
+    #[serde(skip)]
+    pub verified: Option<FileStamp>,
+}
+
+/// Identity and change times of a file, telling whether it changed without reading it. The
+/// mtime can be set back by anyone able to write the file, the ctime cannot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileStamp {
+    pub device: u64,
+    pub inode: u64,
+    pub size: u64,
+    /// Nanoseconds since the unix epoch
+    pub modified_ns: i128,
+    /// Nanoseconds since the unix epoch the inode last changed
+    pub changed_ns: i128,
+}
+
+impl ModelSpec {
+    pub fn new(name: impl Into<String>, path: impl Into<PathBuf>) -> Self {
+        Self {
+            name: name.into(),
+            path: path.into(),
+            context_size: CONTEXT_SIZE,
+            chat_format: ChatFormat::Auto,
+            sampling: Sampling::default(),
+            sha256: None,
+            verified: None,
+        }
+    }
+
+    /// Check the file against the expected sha256 and record it as verified. Reads all of the
+    /// file, so this happens before requests are served rather than when the model is loaded.
+    pub fn verify(&mut self) -> Result<(), NaturalError> {
+        let Some(expected) = &self.sha256 else {
+            return Ok(());
+        };
+
+        let stamp = FileStamp::of(&self.path)?;
+        let actual = gguf::sha256(&self.path).map_err(|e| NaturalError::ModelLoad {
+            message: format!("{}: {e}", self.path.display()),
+        })?;
+
+        if !actual.eq_ignore_ascii_case(expected) {
+            return Err(NaturalError::ModelLoad {
+                message: format!(
+                    "{}: sha256 is {actual}, expected {expected}",
+                    self.path.display()
+                ),
+            });
+        }
+
+        self.verified = Some(stamp);
+
+        Ok(())
+    }
+
+    /// Identifies the answers generated with this model: its file along with the chat format
+    /// and sampling parameters, which change the answers as well
+    pub fn fingerprint(&self) -> Result<String, NaturalError> {
+        let sampling = serde_json::to_string(&self.sampling).map_err(NaturalError::internal)?;
+
+        Ok(fingerprint(&[
+            &self.file_fingerprint()?,
+            &self.chat_format.to_string(),
+            &sampling,
+        ]))
+    }
+
+    /// Identifies the file of the model without reading it: its canonical path, stamp and
+    /// sha256 if known. Changes when the file is replaced, even in place.
+    pub fn file_fingerprint(&self) -> Result<String, NaturalError> {
+        let path = std::fs::canonicalize(&self.path).map_err(|e| NaturalError::ModelLoad {
+            message: format!("{}: {e}", self.path.display()),
+        })?;
+        let stamp = FileStamp::of(&path)?;
+
+        Ok(fingerprint(&[
+            &path.to_string_lossy(),
+            &stamp.device.to_string(),
+            &stamp.inode.to_string(),
+            &stamp.size.to_string(),
+            &stamp.modified_ns.to_string(),
+            &stamp.changed_ns.to_string(),
+            self.sha256.as_deref().unwrap_or_default(),
+        ]))
+    }
+
+    /// Whether both are served by the same loaded model, sampling is chosen per request
+    fn loads_like(&self, other: &ModelSpec) -> bool {
+        self.path == other.path
+            && self.context_size == other.context_size
+            && self.chat_format == other.chat_format
+            // A model loaded unchecked may not be the file expected
+            && self.sha256 == other.sha256
+    }
+}
+
+/// Identifies a request submitted to the pool
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PoolTicket {
+    model: u64,
+    ticket: Ticket,
+}
+
+/// A model along with the generator borrowing it
+struct Loaded {
+    // Declared before the model it borrows, so it is dropped first
+    generator: SqlGenerator<'static>,
+    /// Only kept to outlive the generator
+    _model: Box<LlamaModel>,
+    spec: ModelSpec,
+    id: u64,
+    /// Bytes of memory the model is accounted with
+    size: u64,
+    last_used: Instant,
+}
+
+pub struct Pool<'b> {
+    backend: &'b LlamaBackend,
+    default: ModelSpec,
+    loaded: Vec<Loaded>,
+    next_id: u64,
+    /// Bytes of memory models may take up together, 0 for no limit
+    budget: u64,
+    slots: usize,
+    threads: i32,
+    gpu_layers: u32,
+    dialect: SqlDialect,
+    /// Directory prompt states are persisted in, below a subdirectory per model
+    state_dir: Option<PathBuf>,
+    /// Directories models other than the default have to be in, any path if `None`
+    model_dirs: Option<Vec<PathBuf>>,
+    /// Files along with the sha256 the pool found them to have, as of their stamp
+    verified: HashMap<(PathBuf, String), FileStamp>,
+}
+
+impl<'b> Pool<'b> {
+    /// Pool serving requests without a model with `default`
+    pub fn new(backend: &'b LlamaBackend, default: ModelSpec) -> Self {
+        Self {
+            backend,
+            default,
+            loaded: vec![],
+            next_id: 0,
+            budget: 0,
+            slots: 1,
+            threads: 4,
+            gpu_layers: 512,
+            dialect: SqlDialect::default(),
+            state_dir: None,
+            model_dirs: None,
+            verified: HashMap::new(),
+        }
+    }
+
+    /// Unload models to keep their files within `bytes` in total. A single model exceeding
+    /// the budget is loaded nevertheless.
+    pub fn with_budget(mut self, bytes: u64) -> Self {
+        self.budget = bytes;
+        self
+    }
+
+    /// Serve up to `slots` requests per model concurrently
+    pub fn with_slots(mut self, slots: usize) -> Self {
+        self.slots = slots.max(1);
+        self
+    }
+
+    pub fn with_threads(mut self, threads: i32) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Layers offloaded to the gpu
+    pub fn with_gpu_layers(mut self, layers: u32) -> Self {
+        self.gpu_layers = layers;
+        self
+    }
+
+    pub fn with_dialect(mut self, dialect: SqlDialect) -> Self {
+        self.dialect = dialect;
+        self
+    }
+
+    /// Persist prompt states below `dir`, see [`SqlGenerator::with_state_dir`]
+    pub fn with_state_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.state_dir = Some(dir.into());
+        self
+    }
+
+    /// Refuse requests for models other than the default unless their file is below one of
+    /// `dirs`. Clients of a shared server must not have it read arbitrary files.
+    pub fn with_model_dirs(mut self, dirs: Vec<PathBuf>) -> Self {
+        self.model_dirs = Some(dirs);
+        self
+    }
+
+    /// Load the default model ahead of its first request
+    pub fn preload(&mut self) -> Result<(), NaturalError> {
+        let default = self.default.clone();
+        self.load(&default).map(|_| ())
+    }
+
+    /// Whether no request is in flight on any model
+    pub fn is_idle(&self) -> bool {
+        self.loaded.iter().all(|loaded| loaded.generator.is_idle())
+    }
+
+    /// [`ModelSpec::fingerprint`] of `model`, or the default model
+    pub fn fingerprint(&self, model: Option<&ModelSpec>) -> Result<String, NaturalError> {
+        self.spec(model)?.fingerprint()
+    }
+
+    /// Start generating sql of `dialect`, or the dialect of the pool, with `model`, or the
+    /// default model. Returns `None` if the model has no free slot or does not fit the budget
+    /// yet, the request has to be submitted again.
+    pub fn submit(
+        &mut self,
+        model: Option<&ModelSpec>,
+        dialect: Option<SqlDialect>,
+        prompt: &Prompt,
+        timeout: Option<Duration>,
+        interrupted: impl FnMut() -> bool,
+    ) -> Result<Option<PoolTicket>, NaturalError> {
+        let spec = self.spec(model)?.clone();
+
+        let index = match self.loaded.iter().position(|l| l.spec.loads_like(&spec)) {
+            Some(index) => index,
+            None if self.make_room(FileStamp::of(&spec.path)?.size) => self.load(&spec)?,
+            None => return Ok(None),
+        };
+
+        let loaded = &mut self.loaded[index];
+
+        if !loaded.generator.has_capacity() {
+            return Ok(None);
+        }
+
+        loaded.last_used = Instant::now();
+        loaded.generator.set_sampling(spec.sampling);
+        loaded
+            .generator
+            .set_dialect(dialect.unwrap_or(self.dialect));
+
+        let ticket = loaded.generator.submit(prompt, timeout, interrupted)?;
+
+        Ok(Some(PoolTicket {
+            model: loaded.id,
+            ticket,
+        }))
+    }
+
+    /// Stop generating for a request, see [`SqlGenerator::cancel`]
+    pub fn cancel(&mut self, ticket: PoolTicket) -> bool {
+        self.loaded
+            .iter_mut()
+            .find(|loaded| loaded.id == ticket.model)
+            .is_some_and(|loaded| loaded.generator.cancel(ticket.ticket))
+    }
+
+    /// Step every model, see [`SqlGenerator::step`]
+    pub fn step(&mut self) -> Vec<(PoolTicket, Result<Generation, NaturalError>)> {
+        let mut finished = vec![];
+
+        for loaded in &mut self.loaded {
+            for (ticket, result) in loaded.generator.step() {
+                let ticket = PoolTicket {
+                    model: loaded.id,
+                    ticket,
+                };
+
+                finished.push((ticket, result));
+            }
+        }
+
+        finished
+    }
+
+    /// Generate with a single request, the pool must not serve others.
+    /// See [`SqlGenerator::generate`].
+    pub fn generate(
+        &mut self,
+        model: Option<&ModelSpec>,
+        prompt: &Prompt,
+        timeout: Option<Duration>,
+        mut interrupted: impl FnMut() -> bool,
+    ) -> Result<Generation, NaturalError> {
+        if !self.is_idle() {
+            return Err(NaturalError::internal(
+                "pool is busy serving other requests",
+            ));
+        }
+
+        // An idle pool can always make room
+        let Some(ticket) = self.submit(model, None, prompt, timeout, &mut interrupted)? else {
+            return Err(NaturalError::internal("no slot to generate in"));
+        };
+
+        loop {
+            if interrupted() {
+                self.cancel(ticket);
+                return Err(NaturalError::Cancelled);
+            }
+
+            for (finished, result) in self.step() {
+                if finished == ticket {
+                    return result;
+                }
+            }
+        }
+    }
+
+    /// Unload idle models, least recently used first, until `size` more bytes fit the budget.
+    /// Returns whether they fit.
+    fn make_room(&mut self, size: u64) -> bool {
+        loop {
+            let used = self.loaded.iter().map(|loaded| loaded.size).sum::<u64>();
+
+            if self.budget == 0 || self.loaded.is_empty() || used + size <= self.budget {
+                return true;
+            }
+
+            let Some(lru) = self
+                .loaded
+                .iter()
+                .enumerate()
+                .filter(|(_, loaded)| loaded.generator.is_idle())
+                .min_by_key(|(_, loaded)| loaded.last_used)
+                .map(|(index, _)| index)
+            else {
+                return false;
+            };
+
+            let unloaded = self.loaded.remove(lru);
+            info!(model = unloaded.spec.name, "unloaded model");
+        }
+    }
+
+    /// The model to serve a request for `model` with, if it may be served
+    fn spec<'a>(&'a self, model: Option<&'a ModelSpec>) -> Result<&'a ModelSpec, NaturalError> {
+        let Some(model) = model.filter(|model| !model.loads_like(&self.default)) else {
+            return Ok(&self.default);
+        };
+
+        let Some(dirs) = &self.model_dirs else {
+            return Ok(model);
+        };
+
+        let path = std::fs::canonicalize(&model.path).map_err(|e| NaturalError::ModelLoad {
+            message: format!("{}: {e}", model.path.display()),
+        })?;
+
+        let allowed = dirs
+            .iter()
+            .any(|dir| std::fs::canonicalize(dir).is_ok_and(|dir| path.starts_with(dir)));
+
+        if !allowed {
+            return Err(NaturalError::ModelLoad {
+                message: format!(
+                    "{}: not in a models directory of the server",
+                    model.path.display()
+                ),
+            });
+        }
+
+        Ok(model)
+    }
+
+    /// Load a model, returning its index
+    fn load(&mut self, spec: &ModelSpec) -> Result<usize, NaturalError> {
+        let model_load = |e: &dyn std::fmt::Display| NaturalError::ModelLoad {
+            message: format!("{}: {e}", spec.path.display()),
+        };
+
+        let stamp = FileStamp::of(&spec.path)?;
+
+        if let Some(sha256) = &spec.sha256 {
+            let key = (spec.path.clone(), sha256.to_ascii_lowercase());
+
+            // Hashing stalls the requests in flight, so it only happens for files not seen yet
+            if spec.verified != Some(stamp) && self.verified.get(&key) != Some(&stamp) {
+                info!(model = spec.name, path = %spec.path.display(), "verifying sha256");
+
+                let mut checked = spec.clone();
+                checked.verify()?;
+
+                if checked.verified != Some(stamp) || FileStamp::of(&spec.path)? != stamp {
+                    return Err(model_load(&"changed while its sha256 was verified"));
+                }
+
+                self.verified.insert(key, stamp);
+            }
+        }
+
+        let params = LlamaModelParams::default().with_n_gpu_layers(self.gpu_layers);
+        let model = Box::new(
+            LlamaModel::load_from_file(self.backend, &spec.path, &params)
+                .map_err(|e| model_load(&e))?,
+        );
+
+        // SAFETY: The model is boxed, so its address is stable, and only dropped after the
+        // generator borrowing it, see the field order of Loaded.
+        let borrowed: &'static LlamaModel = unsafe { &*(model.as_ref() as *const LlamaModel) };
+
+        let context = borrowed
+            .new_context(
+                self.backend,
+                LlamaContextParams::default()
+                    .with_n_threads(self.threads)
+                    .with_n_ctx(NonZeroU32::new(spec.context_size * self.slots as u32))
+                    .with_n_seq_max(self.slots as u32),
+            )
+            .map_err(|e| model_load(&e))?;
+
+        let mut generator = SqlGenerator::new(context)?
+            .with_dialect(self.dialect)
+            .with_chat_format(spec.chat_format)
+            .with_slots(self.slots);
+
+        if let Some(dir) = &self.state_dir {
+            generator = generator.with_state_dir(state_dir(dir, spec, self.slots)?);
+        }
+
+        info!(model = spec.name, path = %spec.path.display(), "loaded model");
+
+        self.loaded.push(Loaded {
+            generator,
+            _model: model,
+            spec: spec.clone(),
+            id: self.next_id,
+            size: stamp.size,
+            last_used: Instant::now(),
+        });
+
+        self.next_id += 1;
+
+        Ok(self.loaded.len() - 1)
+    }
+}
+
+impl FileStamp {
+    pub fn of(path: &Path) -> Result<Self, NaturalError> {
+        let metadata = std::fs::metadata(path).map_err(|e| NaturalError::ModelLoad {
+            message: format!("{}: {e}", path.display()),
+        })?;
+
+        let ns = |secs: i64, nsecs: i64| secs as i128 * 1_000_000_000 + nsecs as i128;
+
+        Ok(Self {
+            device: metadata.dev(),
+            inode: metadata.ino(),
+            size: metadata.size(),
+            modified_ns: ns(metadata.mtime(), metadata.mtime_nsec()),
+            changed_ns: ns(metadata.ctime(), metadata.ctime_nsec()),
+        })
+    }
+}
+
+/// Prompt states are only valid for the model file and context layout that produced them. The
+/// file stem merely keeps the directory recognisable.
+fn state_dir(dir: &Path, spec: &ModelSpec, slots: usize) -> Result<PathBuf, NaturalError> {
+    let stem = spec
+        .path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let key = fingerprint(&[
+        &spec.file_fingerprint()?,
+        &spec.context_size.to_string(),
+        &slots.to_string(),
+    ]);
+
+    Ok(dir.join(format!("{stem}-{}", &key[..16])))
+}
@@ -0,0 +1,651 @@
+//! Prompt templates rendered into chat messages.
+//!
+//! Templates use a small mustache-like syntax: `{{name}}` substitutes a value
+//! and `{{#name}} ... {{/name}}` renders its body once per item of a list
+//! (`examples`, `history`) or once if a text value (e.g. `dialect`) is
+//! non-empty. Inside a list section the fields of the current item shadow the
+//! outer ones, so `{{question}}` within `{{#examples}}` refers to the example.
+
+use std::fmt;
+
+use eyre::{bail, eyre, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Instructions given to the model as system turn of the built-in template.
+pub const DEFAULT_SYSTEM: &str = r#"You are an expert SQL query generator that converts natural language to SQL.
+You can only reference tables and columns outlined in the schema!
+Besides a one sentence explanation or a clarifying question, you MUST NOT generate any WORDS
+beyond valid SQL.
+{{#dialect}}
+{{dialect}}
+{{/dialect}}"#;
+
+/// User turn of the built-in template.
+pub const DEFAULT_USER: &str = r#"<schema>{{schema}}</schema>
+{{#examples}}
+<example>
+<question>{{question}}</question>
+<sql>{{sql}}</sql>
+</example>
+{{/examples}}
+{{#history}}
+<previous>
+<question>{{question}}</question>
+{{#sql}}
+<sql>{{sql}}</sql>
+{{/sql}}
+{{#clarification}}
+{{clarification}}
+{{/clarification}}
+{{#summary}}
+<result>{{summary}}</result>
+{{/summary}}
+</previous>
+{{/history}}
+<question>{{question}}</question>
+
+Based on the schema, generate the most efficient SQL query that answers the question.
+Explain in one sentence what the query computes before writing it.
+If the question answers a clarifying question you asked before, combine both.
+
+<explanation>
+[WHAT THE QUERY COMPUTES]
+</explanation>
+<sql>
+[YOUR OUTPUT SQL QUERY]
+</sql>
+
+If the question is ambiguous, do not guess. Ask back instead of writing SQL:
+
+<clarify>
+<question>[WHAT IS UNCLEAR]</question>
+<option>[A POSSIBLE MEANING]</option>
+</clarify>"#;
+
+/// Instructions of the built-in template explaining sql.
+pub const EXPLAIN_SYSTEM: &str = r#"You are an expert SQL reviewer that explains SQL queries in plain English.
+You can only reference tables and columns outlined in the schema!
+Describe what the query computes for a reader who does not know SQL, not how it is executed.
+{{#dialect}}
+{{dialect}}
+{{/dialect}}"#;
+
+/// User turn of the built-in template explaining sql.
+pub const EXPLAIN_USER: &str = r#"<schema>{{schema}}</schema>
+<sql>{{sql}}</sql>
+
+Based on the schema, explain in a few sentences what the query computes.
+
+<explanation>
+[WHAT THE QUERY COMPUTES]
+</explanation>"#;
+
+/// Stop sequences of templates answering in `<sql>` tags. Generation ends once the tag is
+/// closed, a statement is terminated or a clarifying question is asked.
+pub const DEFAULT_STOP: &[&str] = &["</sql>", ";\n", "</clarify>"];
+
+/// A question answered by a known good query, used for few-shot prompting.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Example {
+    pub question: String,
+    pub sql: String,
+}
+
+/// A previous question of a conversation and the sql generated for it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Turn {
+    pub question: String,
+    /// Empty if the model asked a clarifying question instead
+    pub sql: String,
+    /// The `<clarify>` block the model answered with, if any
+    pub clarification: Option<String>,
+    /// Short description of the result, e.g. its row count
+    pub summary: Option<String>,
+}
+
+/// Everything a template can reference.
+#[derive(Clone, Debug, Default)]
+pub struct PromptContext {
+    pub schema: String,
+    pub question: String,
+    pub dialect: String,
+    pub examples: Vec<Example>,
+    pub history: Vec<Turn>,
+    /// Sql to explain, see [`Task::Explain`]
+    pub sql: String,
+}
+
+/// What the model is asked to produce.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Task {
+    /// Sql answering the question, or a clarifying question
+    #[default]
+    Sql,
+    /// A plain language description of the sql, in `<explanation>` tags
+    Explain,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::System => "system",
+            Self::User => "user",
+            Self::Assistant => "assistant",
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Message {
+    pub role: Role,
+    pub content: String,
+}
+
+/// A rendered prompt, ready to be formatted with the models chat template.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Prompt {
+    pub messages: Vec<Message>,
+    /// Identifies the static part of the prompt (template, schema, examples), prompts sharing it
+    /// can reuse the evaluated prefix.
+    pub cache_key: Option<String>,
+    /// Generation ends as soon as the output contains one of these
+    #[serde(default)]
+    pub stop: Vec<String>,
+    #[serde(default)]
+    pub task: Task,
+}
+
+impl fmt::Display for Prompt {
+    /// Plain text rendering for models without a chat template
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, message) in self.messages.iter().enumerate() {
+            if i > 0 {
+                f.write_str("\n\n")?;
+            }
+
+            f.write_str(&message.content)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A named pair of system and user templates.
+#[derive(Clone, Debug)]
+pub struct PromptTemplate {
+    name: String,
+    system: Template,
+    user: Template,
+    stop: Vec<String>,
+    task: Task,
+}
+
+impl PromptTemplate {
+    pub fn new(name: impl Into<String>, system: &str, user: &str) -> Result<Self> {
+        let name = name.into();
+
+        Ok(Self {
+            system: Template::parse(system)
+                .map_err(|e| eyre!("Invalid system template {name:?}: {e}"))?,
+            user: Template::parse(user)
+                .map_err(|e| eyre!("Invalid user template {name:?}: {e}"))?,
+            name,
+            stop: DEFAULT_STOP.iter().map(|s| s.to_string()).collect(),
+            task: Task::Sql,
+        })
+    }
+
+    /// The built-in template explaining the sql of the context instead of generating it
+    pub fn explain() -> Self {
+        Self::new("explain", EXPLAIN_SYSTEM, EXPLAIN_USER)
+            .expect("the built-in template must be valid")
+            .with_stop_sequences(vec!["</explanation>".to_string()])
+            .with_task(Task::Explain)
+    }
+
+    /// Replace the default stop sequences, e.g. for templates not answering in `<sql>` tags
+    pub fn with_stop_sequences(mut self, stop: Vec<String>) -> Self {
+        self.stop = stop;
+        self
+    }
+
+    pub fn with_task(mut self, task: Task) -> Self {
+        self.task = task;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn render(&self, context: &PromptContext) -> Result<Prompt> {
+        let mut messages = vec![];
+
+        for (role, template) in [(Role::System, &self.system), (Role::User, &self.user)] {
+            let content = template.render(context)?;
+
+            if !content.trim().is_empty() {
+                messages.push(Message {
+                    role,
+                    content: content.trim().to_string(),
+                });
+            }
+        }
+
+        Ok(Prompt {
+            messages,
+            cache_key: Some(self.cache_key(context)),
+            stop: self.stop.clone(),
+            task: self.task,
+        })
+    }
+
+    fn cache_key(&self, context: &PromptContext) -> String {
+        let mut parts = vec![
+            self.name.as_str(),
+            self.system.source.as_str(),
+            self.user.source.as_str(),
+            context.schema.as_str(),
+            context.dialect.as_str(),
+        ];
+
+        for example in &context.examples {
+            parts.push(&example.question);
+            parts.push(&example.sql);
+        }
+
+        fingerprint(&parts)
+    }
+}
+
+impl Default for PromptTemplate {
+    fn default() -> Self {
+        Self::new("default", DEFAULT_SYSTEM, DEFAULT_USER)
+            .expect("the built-in template must be valid")
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Node {
+    Text(String),
+    Var(String),
+    Section { name: String, body: Vec<Node> },
+}
+
+#[derive(Clone, Debug)]
+struct Template {
+    source: String,
+    nodes: Vec<Node>,
+}
+
+impl Template {
+    fn parse(source: &str) -> Result<Self> {
+        // Stack of open sections, the root is never popped
+        let mut stack: Vec<(String, Vec<Node>)> = vec![(String::new(), vec![])];
+        let mut rest = source;
+
+        while let Some(start) = rest.find("{{") {
+            let (text, tail) = rest.split_at(start);
+
+            if !text.is_empty() {
+                stack
+                    .last_mut()
+                    .unwrap()
+                    .1
+                    .push(Node::Text(text.to_string()));
+            }
+
+            let end = tail
+                .find("}}")
+                .ok_or_else(|| eyre!("Unclosed tag at {:?}", truncate(tail)))?;
+
+            let tag = tail[2..end].trim();
+            rest = &tail[end + 2..];
+
+            // Section tags on a line of their own do not leave an empty line behind
+            let offset = source.len() - tail.len();
+            let standalone = source[..offset].ends_with('\n') || offset == 0;
+
+            if let Some(name) = tag.strip_prefix('#') {
+                stack.push((name.trim().to_string(), vec![]));
+
+                if standalone {
+                    rest = rest.strip_prefix('\n').unwrap_or(rest);
+                }
+            } else if let Some(name) = tag.strip_prefix('/') {
+                let name = name.trim();
+
+                if stack.len() == 1 {
+                    bail!("Closing tag {{{{/{name}}}}} without an opening tag");
+                }
+
+                let (open, body) = stack.pop().unwrap();
+
+                if open != name {
+                    bail!("Closing tag {{{{/{name}}}}} does not match {{{{#{open}}}}}");
+                }
+
+                stack
+                    .last_mut()
+                    .unwrap()
+                    .1
+                    .push(Node::Section { name: open, body });
+
+                if standalone {
+                    rest = rest.strip_prefix('\n').unwrap_or(rest);
+                }
+            } else if tag.is_empty() {
+                bail!("Empty tag");
+            } else {
+                stack.last_mut().unwrap().1.push(Node::Var(tag.to_string()));
+            }
+        }
+
+        if !rest.is_empty() {
+            stack
+                .last_mut()
+                .unwrap()
+                .1
+                .push(Node::Text(rest.to_string()));
+        }
+
+        if stack.len() > 1 {
+            let (open, _) = stack.pop().unwrap();
+            bail!("Section {{{{#{open}}}}} is never closed");
+        }
+
+        let (_, nodes) = stack.pop().unwrap();
+
+        Ok(Self {
+            source: source.to_string(),
+            nodes,
+        })
+    }
+
+    fn render(&self, context: &PromptContext) -> Result<String> {
+        let mut out = String::new();
+        render(&self.nodes, &[context], &mut out)?;
+        Ok(out)
+    }
+}
+
+/// Canonical form of a question, so trivially different spellings share cached answers
+pub fn normalize_question(question: &str) -> String {
+    question
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .trim_end_matches(['?', '.', '!', ' '])
+        .to_lowercase()
+}
+
+/// Hex encoded sha256 over a sequence of strings
+pub fn fingerprint(parts: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+
+    for part in parts {
+        // Length prefixes keep ("ab", "c") and ("a", "bc") apart
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part.as_bytes());
+    }
+
+    format!("{:x}", hasher.finalize())
+}
+
+fn truncate(s: &str) -> &str {
+    s.char_indices().nth(32).map_or(s, |(i, _)| &s[..i])
+}
+
+/// Values templates can look up by name
+trait Scope {
+    fn text(&self, name: &str) -> Option<&str>;
+
+    fn items(&self, _name: &str) -> Option<Vec<&dyn Scope>> {
+        None
+    }
+}
+
+impl Scope for PromptContext {
+    fn text(&self, name: &str) -> Option<&str> {
+        match name {
+            "schema" => Some(&self.schema),
+            "question" => Some(&self.question),
+            "dialect" => Some(&self.dialect),
+            "sql" => Some(&self.sql),
+            _ => None,
+        }
+    }
+
+    fn items(&self, name: &str) -> Option<Vec<&dyn Scope>> {
+        match name {
+            "examples" => Some(self.examples.iter().map(|e| e as &dyn Scope).collect()),
+            "history" => Some(self.history.iter().map(|t| t as &dyn Scope).collect()),
+            _ => None,
+        }
+    }
+}
+
+impl Scope for Example {
+    fn text(&self, name: &str) -> Option<&str> {
+        match name {
+            "question" => Some(&self.question),
+            "sql" => Some(&self.sql),
+            _ => None,
+        }
+    }
+}
+
+impl Scope for Turn {
+    fn text(&self, name: &str) -> Option<&str> {
+        match name {
+            "question" => Some(&self.question),
+            "sql" => Some(&self.sql),
+            "clarification" => Some(self.clarification.as_deref().unwrap_or_default()),
+            "summary" => Some(self.summary.as_deref().unwrap_or_default()),
+            _ => None,
+        }
+    }
+}
+
+fn render(nodes: &[Node], scopes: &[&dyn Scope], out: &mut String) -> Result<()> {
+    for node in nodes {
+        match node {
+            Node::Text(text) => out.push_str(text),
+            Node::Var(name) => {
+                let value = scopes
+                    .iter()
+                    .rev()
+                    .find_map(|scope| scope.text(name))
+                    .ok_or_else(|| eyre!("Unknown template variable {{{{{name}}}}}"))?;
+
+                out.push_str(value);
+            }
+            Node::Section { name, body } => {
+                if let Some(items) = scopes.iter().rev().find_map(|scope| scope.items(name)) {
+                    for item in items {
+                        let mut inner = scopes.to_vec();
+                        inner.push(item);
+                        render(body, &inner, out)?;
+                    }
+                } else if let Some(text) = scopes.iter().rev().find_map(|scope| scope.text(name)) {
+                    if !text.trim().is_empty() {
+                        render(body, scopes, out)?;
+                    }
+                } else {
+                    bail!("Unknown template section {{{{#{name}}}}}");
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context() -> PromptContext {
+        PromptContext {
+            schema: "CREATE TABLE users (id INT);".into(),
+            question: "How many users are there?".into(),
+            dialect: String::new(),
+            examples: vec![Example {
+                question: "List all users".into(),
+                sql: "SELECT * FROM users;".into(),
+            }],
+            history: vec![],
+            sql: String::new(),
+        }
+    }
+
+    #[test]
+    fn sections_shadow_outer_values() {
+        let template = PromptTemplate::new(
+            "test",
+            "{{#dialect}}never rendered{{/dialect}}",
+            "{{#examples}}\n{{question}} -> {{sql}}\n{{/examples}}\nQ: {{question}}",
+        )
+        .unwrap();
+
+        let prompt = template.render(&context()).unwrap();
+
+        assert_eq!(
+            prompt.messages,
+            vec![Message {
+                role: Role::User,
+                content: "List all users -> SELECT * FROM users;\nQ: How many users are there?"
+                    .into(),
+            }]
+        );
+    }
+
+    #[test]
+    fn history_includes_optional_summaries() {
+        let mut context = context();
+        context.history = vec![
+            Turn {
+                question: "Count users".into(),
+                sql: "SELECT count(*) FROM users;".into(),
+                summary: Some("1 row".into()),
+                ..Default::default()
+            },
+            Turn {
+                question: "Count orders".into(),
+                sql: "SELECT count(*) FROM orders;".into(),
+                ..Default::default()
+            },
+        ];
+
+        let template = PromptTemplate::new(
+            "t",
+            "",
+            "{{#history}}\n{{question}}{{#summary}} ({{summary}}){{/summary}}\n{{/history}}",
+        )
+        .unwrap();
+
+        let prompt = template.render(&context).unwrap();
+
+        assert_eq!(
+            prompt.messages[0].content,
+            "Count users (1 row)\nCount orders"
+        );
+    }
+
+    #[test]
+    fn history_includes_clarifying_questions() {
+        let mut context = context();
+        context.history = vec![Turn {
+            question: "Top customers".into(),
+            clarification: Some("<clarify>\n<question>By revenue?</question>\n</clarify>".into()),
+            ..Default::default()
+        }];
+
+        let prompt = PromptTemplate::default().render(&context).unwrap();
+
+        assert!(prompt.messages[1].content.contains(
+            "<previous>\n<question>Top customers</question>\n<clarify>\n\
+             <question>By revenue?</question>\n</clarify>\n</previous>"
+        ));
+    }
+
+    #[test]
+    fn explain_template_renders_the_sql() {
+        let mut context = context();
+        context.sql = "SELECT count(*) FROM users".into();
+
+        let prompt = PromptTemplate::explain().render(&context).unwrap();
+
+        assert_eq!(prompt.task, Task::Explain);
+        assert!(prompt.messages[1]
+            .content
+            .contains("<sql>SELECT count(*) FROM users</sql>"));
+    }
+
+    #[test]
+    fn rejects_malformed_templates() {
+        assert!(PromptTemplate::new("t", "{{#examples}}", "").is_err());
+        assert!(PromptTemplate::new("t", "{{/examples}}", "").is_err());
+        assert!(PromptTemplate::new("t", "{{#a}}{{/b}}", "").is_err());
+        assert!(PromptTemplate::new("t", "{{schema", "").is_err());
+    }
+
+    #[test]
+    fn cache_key_ignores_question_and_history() {
+        let template = PromptTemplate::default();
+
+        let mut other = context();
+        other.question = "How many orders are there?".into();
+        other.history.push(Turn::default());
+
+        let mut schema = context();
+        schema.schema = "CREATE TABLE orders (id INT);".into();
+
+        let key = |c: &PromptContext| template.render(c).unwrap().cache_key;
+
+        assert_eq!(key(&context()), key(&other));
+        assert_ne!(key(&context()), key(&schema));
+    }
+
+    #[test]
+    fn normalizes_questions() {
+        assert_eq!(
+            normalize_question("  How many   Users are there?? "),
+            "how many users are there"
+        );
+        assert_eq!(
+            normalize_question("how many users are there"),
+            "how many users are there"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_variables() {
+        let template = PromptTemplate::new("t", "", "{{tables}}").unwrap();
+
+        assert!(template.render(&context()).is_err());
+    }
+
+    #[test]
+    fn default_template_renders() {
+        let prompt = PromptTemplate::default().render(&context()).unwrap();
+
+        assert_eq!(prompt.messages.len(), 2);
+        assert!(prompt.messages[1]
+            .content
+            .contains("<question>List all users</question>"));
+        assert!(prompt.messages[1]
+            .content
+            .contains("<question>How many users are there?</question>"));
+    }
+}
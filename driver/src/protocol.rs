@@ -0,0 +1,201 @@
+//! Wire protocol between clients and an inference server.
+//!
+//! Messages are newline delimited JSON over a unix domain socket. A client writes one
+//! [`Message`] per line and receives one [`Response`] line for it on the same connection.
+//! Closing the connection while waiting for the response cancels the request.
+
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+
+use eyre::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::dialect::SqlDialect;
+use crate::error::NaturalError;
+use crate::generator::Generation;
+use crate::pool::ModelSpec;
+use crate::prompt::Prompt;
+
+/// Socket the daemon listens on unless configured otherwise. The daemon creates its directory
+/// accessible to its user only, so it runs as the user postgres runs as.
+pub const DAEMON_SOCKET: &str = "/run/natural/daemon.sock";
+
+/// How often a waiting client checks whether it was interrupted
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    Generate(Request),
+    /// Ask for the [`fingerprint`](ModelSpec::fingerprint) of `model`, or of the default model
+    /// of the server if `None`, answered by [`Response::Identity`]
+    Identify {
+        #[serde(default)]
+        model: Option<ModelSpec>,
+    },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Request {
+    pub prompt: Prompt,
+    /// Milliseconds after which generation fails with a timeout
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    /// Model to generate with, the default model of the server if `None`
+    #[serde(default)]
+    pub model: Option<ModelSpec>,
+    /// Dialect the sql is parsed and validated as, the dialect of the server if `None`
+    #[serde(default)]
+    pub dialect: Option<SqlDialect>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Response {
+    Ok(Generation),
+    Identity { fingerprint: String },
+    Error { error: NaturalError },
+}
+
+impl From<Result<Generation, NaturalError>> for Response {
+    fn from(result: Result<Generation, NaturalError>) -> Self {
+        match result {
+            Ok(generation) => Self::Ok(generation),
+            Err(error) => Self::Error { error },
+        }
+    }
+}
+
+/// Connection to an inference server
+pub struct Client {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+    dialect: Option<SqlDialect>,
+}
+
+impl Client {
+    pub fn connect(path: impl AsRef<Path>) -> Result<Self, NaturalError> {
+        let path = path.as_ref();
+
+        let stream = UnixStream::connect(path)
+            .with_context(|| format!("failed to connect to the inference server at {path:?}"))
+            .map_err(NaturalError::unavailable)?;
+
+        Ok(Self {
+            reader: BufReader::new(stream.try_clone().map_err(NaturalError::unavailable)?),
+            writer: stream,
+            dialect: None,
+        })
+    }
+
+    /// Request sql of `dialect` rather than of the dialect the server was started with
+    pub fn with_dialect(mut self, dialect: SqlDialect) -> Self {
+        self.dialect = Some(dialect);
+        self
+    }
+
+    /// Generate sql for a prompt with `model`, or the default model of the server, blocking
+    /// until the server answers or `interrupted` returns true. An interrupted client has to be
+    /// dropped, which cancels the request.
+    pub fn generate(
+        &mut self,
+        model: Option<&ModelSpec>,
+        prompt: &Prompt,
+        timeout: Option<Duration>,
+        interrupted: impl FnMut() -> bool,
+    ) -> Result<Generation, NaturalError> {
+        let request = Request {
+            prompt: prompt.clone(),
+            timeout_ms: timeout.map(|timeout| timeout.as_millis() as u64),
+            model: model.cloned(),
+            dialect: self.dialect,
+        };
+
+        match self.send(&Message::Generate(request), interrupted)? {
+            Response::Ok(generation) => Ok(generation),
+            Response::Error { error } => Err(error),
+            response => Err(unexpected(&response)),
+        }
+    }
+
+    /// Identify the model the server generates with for `model`, or for its default model.
+    /// Unlike the path of a model, this tells whether the server replaced its file.
+    pub fn identify(&mut self, model: Option<&ModelSpec>) -> Result<String, NaturalError> {
+        let message = Message::Identify {
+            model: model.cloned(),
+        };
+
+        match self.send(&message, || false)? {
+            Response::Identity { fingerprint } => Ok(fingerprint),
+            Response::Error { error } => Err(error),
+            response => Err(unexpected(&response)),
+        }
+    }
+
+    fn send(
+        &mut self,
+        message: &Message,
+        mut interrupted: impl FnMut() -> bool,
+    ) -> Result<Response, NaturalError> {
+        write(&mut self.writer, message).map_err(NaturalError::unavailable)?;
+
+        self.reader
+            .get_ref()
+            .set_read_timeout(Some(POLL_INTERVAL))
+            .map_err(NaturalError::unavailable)?;
+
+        // Bytes of a partially received line are kept by read_until across timeouts
+        let mut line = vec![];
+
+        while !line.ends_with(b"\n") {
+            match self.reader.read_until(b'\n', &mut line) {
+                Ok(0) => {
+                    return Err(NaturalError::unavailable(
+                        "inference server closed the connection",
+                    ))
+                }
+                Ok(_) => {}
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    if interrupted() {
+                        return Err(NaturalError::Cancelled);
+                    }
+                }
+                Err(e) => return Err(NaturalError::unavailable(e)),
+            }
+        }
+
+        serde_json::from_slice(&line).map_err(NaturalError::unavailable)
+    }
+}
+
+fn unexpected(response: &Response) -> NaturalError {
+    NaturalError::unavailable(format!(
+        "unexpected response from the inference server: {response:?}"
+    ))
+}
+
+/// Write a message as a single line
+pub fn write<T: Serialize>(writer: &mut impl Write, message: &T) -> Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+
+    writer.write_all(&line)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Read the next message, `None` once the peer closed the connection
+pub fn read<T: for<'de> Deserialize<'de>>(reader: &mut impl BufRead) -> Result<Option<T>> {
+    let mut line = String::new();
+
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(
+        serde_json::from_str(&line).context("malformed message")?,
+    ))
+}
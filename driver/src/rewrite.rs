@@ -0,0 +1,337 @@
+//! Rewrites making generated statements bounded and deterministic, regardless of how the
+//! model wrote them.
+
+use std::ops::ControlFlow;
+
+use sqlparser::ast::{
+    visit_relations_mut, Expr, GroupByExpr, ObjectName, SelectItem, SetExpr, Statement,
+};
+use sqlparser::dialect::{Dialect, GenericDialect};
+use sqlparser::keywords::ALL_KEYWORDS;
+use sqlparser::parser::{Parser, ParserError};
+use sqlparser::tokenizer::{Token, Tokenizer};
+
+use crate::inspect;
+
+/// Phrases of a question explicitly asking for every row, whose result must not be cut off.
+/// Words like "each" or "every" alone usually ask for a row per group, e.g. "count orders for
+/// each customer", and do not count.
+const ALL_ROWS: &[&str] = &[
+    "all rows",
+    "all the rows",
+    "all records",
+    "all the records",
+    "every row",
+    "every record",
+    "without limit",
+    "without a limit",
+    "without any limit",
+    "no limit",
+];
+
+/// Aggregates returning a single row when used without `GROUP BY`
+const AGGREGATES: &[&str] = &[
+    "count",
+    "sum",
+    "avg",
+    "min",
+    "max",
+    "array_agg",
+    "string_agg",
+    "json_agg",
+    "jsonb_agg",
+    "bool_and",
+    "bool_or",
+    "every",
+    "stddev",
+    "variance",
+];
+
+/// Rewrites a statement, applying in order:
+///
+/// 1. bound top level queries with a `LIMIT`, see [`limit`]
+/// 2. qualify tables with the schema they resolve to
+/// 3. quote only identifiers that require quoting, see [`normalize_quoting`]
+#[derive(Default)]
+pub struct Rewriter<'r> {
+    limit: Option<u64>,
+    resolve: Option<Resolver<'r>>,
+}
+
+/// Looks up the schema of a table
+type Resolver<'r> = Box<dyn FnMut(&str) -> Option<String> + 'r>;
+
+impl<'r> Rewriter<'r> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bound queries to at most `rows` rows
+    pub fn with_limit(mut self, rows: u64) -> Self {
+        self.limit = Some(rows);
+        self
+    }
+
+    /// Qualify tables not qualified by a schema. `resolve` is passed the name of a table as
+    /// written in sql and returns its schema, quoted if required, or `None` if it is unknown.
+    pub fn with_schema_resolver(
+        mut self,
+        resolve: impl FnMut(&str) -> Option<String> + 'r,
+    ) -> Self {
+        self.resolve = Some(Box::new(resolve));
+        self
+    }
+
+    /// Rewrite sql holding a single statement
+    pub fn rewrite(&mut self, sql: &str, dialect: &dyn Dialect) -> Result<String, ParserError> {
+        let mut statements = Parser::parse_sql(dialect, sql)?;
+        let count = statements.len();
+
+        let [statement] = statements.as_mut_slice() else {
+            return Err(ParserError::ParserError(format!(
+                "expected exactly one statement, got {count}"
+            )));
+        };
+
+        if let Some(rows) = self.limit {
+            limit(statement, rows);
+        }
+
+        if let Some(resolve) = self.resolve.as_mut() {
+            qualify(statement, dialect, resolve);
+        }
+
+        Ok(normalize_quoting(&statement.to_string(), dialect))
+    }
+}
+
+/// Bound a query to `rows` rows, adding a `LIMIT` clause or lowering the one it has. Returns
+/// false, leaving the statement untouched, if it is no query, already bounded or only computes
+/// aggregates returning a single row.
+pub fn limit(statement: &mut Statement, rows: u64) -> bool {
+    let Statement::Query(query) = statement else {
+        return false;
+    };
+
+    if query.fetch.is_some() || is_aggregate_only(&query.body) {
+        return false;
+    }
+
+    let bounded = query
+        .limit
+        .as_ref()
+        .and_then(|limit| limit.to_string().parse::<u64>().ok())
+        .is_some_and(|limit| limit <= rows);
+
+    if bounded {
+        return false;
+    }
+
+    let Ok(limit) = Parser::new(&GenericDialect {})
+        .try_with_sql(&rows.to_string())
+        .and_then(|mut parser| parser.parse_expr())
+    else {
+        return false;
+    };
+
+    query.limit = Some(limit);
+
+    true
+}
+
+/// Quote only identifiers that are not plain lowercase words, using double quotes. Sql the
+/// tokenizer cannot reproduce exactly, e.g. holding escape strings, is returned as is.
+pub fn normalize_quoting(sql: &str, dialect: &dyn Dialect) -> String {
+    let Ok(tokens) = Tokenizer::new(dialect, sql).with_unescape(false).tokenize() else {
+        return sql.to_string();
+    };
+
+    if tokens.iter().map(Token::to_string).collect::<String>() != sql {
+        return sql.to_string();
+    }
+
+    tokens
+        .iter()
+        .map(|token| match token {
+            Token::Word(word) if word.quote_style.is_some() => {
+                if is_plain(&word.value) {
+                    word.value.clone()
+                } else {
+                    // Without unescaping, quotes within the value are still doubled
+                    format!("\"{}\"", word.value)
+                }
+            }
+            token => token.to_string(),
+        })
+        .collect()
+}
+
+/// Whether a question explicitly asks for every row rather than a sample, e.g. "list all rows
+/// of users"
+pub fn asks_for_all_rows(question: &str) -> bool {
+    let words = question
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    // Padded so phrases only match whole words
+    let words = format!(" {words} ");
+
+    ALL_ROWS
+        .iter()
+        .any(|phrase| words.contains(&format!(" {phrase} ")))
+}
+
+fn qualify(
+    statement: &mut Statement,
+    dialect: &dyn Dialect,
+    resolve: &mut dyn FnMut(&str) -> Option<String>,
+) {
+    let ctes = inspect::ctes(statement);
+
+    let _ = visit_relations_mut(statement, |relation: &mut ObjectName| {
+        let name = relation.to_string();
+
+        if relation.0.len() == 1 && !inspect::is_cte(&ctes, &name) {
+            let qualified = resolve(&name).and_then(|schema| {
+                Parser::new(dialect)
+                    .try_with_sql(&format!("{schema}.{name}"))
+                    .and_then(|mut parser| parser.parse_object_name(false))
+                    .ok()
+            });
+
+            if let Some(qualified) = qualified {
+                *relation = qualified;
+            }
+        }
+
+        ControlFlow::<()>::Continue(())
+    });
+}
+
+fn is_aggregate_only(body: &SetExpr) -> bool {
+    let SetExpr::Select(select) = body else {
+        return false;
+    };
+
+    let GroupByExpr::Expressions(group_by, _) = &select.group_by else {
+        return false;
+    };
+
+    group_by.is_empty()
+        && !select.projection.is_empty()
+        && select.projection.iter().all(|item| match item {
+            SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => {
+                is_aggregate(expr)
+            }
+            _ => false,
+        })
+}
+
+fn is_aggregate(expr: &Expr) -> bool {
+    match expr {
+        Expr::Function(function) => {
+            let name = function.name.to_string();
+
+            function.over.is_none() && AGGREGATES.iter().any(|a| name.eq_ignore_ascii_case(a))
+        }
+        Expr::Nested(expr) | Expr::Cast { expr, .. } => is_aggregate(expr),
+        _ => false,
+    }
+}
+
+/// Whether an identifier can be written without quotes
+fn is_plain(ident: &str) -> bool {
+    let mut chars = ident.chars();
+
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_lowercase() || c == '_')
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        && ALL_KEYWORDS
+            .binary_search(&ident.to_ascii_uppercase().as_str())
+            .is_err()
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlparser::dialect::PostgreSqlDialect;
+
+    use super::*;
+
+    fn limited(sql: &str, rows: u64) -> Option<String> {
+        let mut statement = Parser::parse_sql(&PostgreSqlDialect {}, sql)
+            .unwrap()
+            .remove(0);
+        limit(&mut statement, rows).then(|| statement.to_string())
+    }
+
+    #[test]
+    fn limits_unbounded_queries() {
+        assert_eq!(
+            limited("SELECT * FROM users ORDER BY id", 100).as_deref(),
+            Some("SELECT * FROM users ORDER BY id LIMIT 100")
+        );
+        assert_eq!(
+            limited("SELECT * FROM users FOR UPDATE", 10).as_deref(),
+            Some("SELECT * FROM users LIMIT 10 FOR UPDATE")
+        );
+
+        assert_eq!(
+            limited("SELECT * FROM users LIMIT 500", 100).as_deref(),
+            Some("SELECT * FROM users LIMIT 100")
+        );
+
+        assert_eq!(limited("SELECT * FROM users LIMIT 5", 100), None);
+        assert_eq!(
+            limited("SELECT count(*), max(id) AS last FROM users", 100),
+            None
+        );
+        assert_eq!(limited("DELETE FROM users", 100), None);
+    }
+
+    #[test]
+    fn qualifies_tables_and_normalizes_quoting() {
+        let mut rewriter = Rewriter::new()
+            .with_limit(1000)
+            .with_schema_resolver(|table| (table != "missing").then(|| "\"Sales\"".to_string()));
+
+        let rewrite =
+            |rewriter: &mut Rewriter, sql| rewriter.rewrite(sql, &PostgreSqlDialect {}).unwrap();
+
+        assert_eq!(
+            rewrite(
+                &mut rewriter,
+                r#"WITH "recent" AS (SELECT * FROM "orders") SELECT "Name", "user" FROM "users" JOIN recent ON true JOIN missing ON true"#
+            ),
+            r#"WITH recent AS (SELECT * FROM "Sales".orders) SELECT "Name", "user" FROM "Sales".users JOIN recent ON true JOIN missing ON true LIMIT 1000"#
+        );
+
+        assert_eq!(
+            rewrite(&mut rewriter, "SELECT E'a\\nb' FROM \"t\""),
+            "SELECT E'a\\nb' FROM \"Sales\".\"t\" LIMIT 1000"
+        );
+
+        assert!(rewriter
+            .rewrite("SELECT 1; SELECT 2", &PostgreSqlDialect {})
+            .is_err());
+    }
+
+    #[test]
+    fn recognizes_questions_for_all_rows() {
+        assert!(asks_for_all_rows("List all rows of users"));
+        assert!(asks_for_all_rows("Every record in orders, without a limit"));
+        assert!(asks_for_all_rows("Orders of 2024, no LIMIT"));
+        assert!(!asks_for_all_rows("Which users ordered recently?"));
+        assert!(!asks_for_all_rows("Show the smallest orders"));
+
+        // Rows per group, not all rows
+        assert!(!asks_for_all_rows("Count orders for each customer"));
+        assert!(!asks_for_all_rows("What did every customer order?"));
+        assert!(!asks_for_all_rows("Total revenue of every product"));
+        assert!(!asks_for_all_rows("Show complete orders"));
+        assert!(!asks_for_all_rows("Users with no limits on their account"));
+    }
+}
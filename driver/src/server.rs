@@ -0,0 +1,277 @@
+//! Inference server answering [`protocol`](crate::protocol) requests with a [`Pool`] of models.
+//!
+//! Connections are handled on background threads which forward requests to the thread
+//! running the models. Requests are submitted as soon as their model has a free slot and are
+//! stepped together, so clients waiting on answers are served in parallel. Requests of clients
+//! that hang up are cancelled.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::DirBuilder;
+use std::io::{BufRead, BufReader, ErrorKind};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{bounded, unbounded, Receiver, RecvTimeoutError, Sender};
+use eyre::{bail, Result};
+use tracing::{debug, warn};
+
+use crate::error::NaturalError;
+use crate::pool::{Pool, PoolTicket};
+use crate::protocol::{self, Message, Response};
+
+/// How often an idle server checks whether it should keep running
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+struct Job {
+    message: Message,
+    reply: Sender<Response>,
+    received: Instant,
+    /// Set once the client hung up
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Job {
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+pub struct Server {
+    listener: UnixListener,
+}
+
+impl Server {
+    /// Listen on `path`. A missing directory is created accessible to the user only, clients
+    /// of other users need access granted to the directory explicitly.
+    pub fn bind(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            if !dir.exists() {
+                DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+            } else if dir.metadata()?.permissions().mode() & 0o002 != 0 {
+                warn!(
+                    "{} is writable by anyone, the socket may be replaced",
+                    dir.display()
+                );
+            }
+        }
+
+        // A socket left behind by a previous server prevents binding, anything else stays
+        if let Ok(metadata) = std::fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                bail!("{} exists and is not a socket", path.display());
+            }
+
+            if UnixStream::connect(path).is_ok() {
+                bail!("{} is served by another server", path.display());
+            }
+
+            std::fs::remove_file(path)?;
+        }
+
+        Ok(Self {
+            listener: UnixListener::bind(path)?,
+        })
+    }
+
+    /// Serve requests on the calling thread until `running` returns false
+    pub fn serve(self, pool: &mut Pool, running: impl FnMut() -> bool) -> Result<()> {
+        let (jobs, queue) = unbounded::<Job>();
+
+        let listener = self.listener;
+        thread::spawn(move || accept(listener, jobs));
+
+        schedule(pool, queue, running)
+    }
+}
+
+fn schedule(pool: &mut Pool, jobs: Receiver<Job>, mut running: impl FnMut() -> bool) -> Result<()> {
+    let mut queue = VecDeque::<Job>::new();
+    // Submitted requests along with their queue time
+    let mut waiting = HashMap::<PoolTicket, (Job, Duration)>::new();
+
+    while running() {
+        if pool.is_idle() && queue.is_empty() {
+            match jobs.recv_timeout(POLL_INTERVAL) {
+                Ok(job) => queue.push_back(job),
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+
+        queue.extend(jobs.try_iter());
+        queue.retain(|job| !job.is_cancelled());
+
+        waiting.retain(|ticket, (job, _)| {
+            if job.is_cancelled() {
+                pool.cancel(*ticket);
+            }
+
+            !job.is_cancelled()
+        });
+
+        // Jobs whose model is busy or does not fit yet stay queued
+        for job in std::mem::take(&mut queue) {
+            let request = match &job.message {
+                Message::Generate(request) => request,
+                Message::Identify { model } => {
+                    let response = match pool.fingerprint(model.as_ref()) {
+                        Ok(fingerprint) => Response::Identity { fingerprint },
+                        Err(error) => Response::Error { error },
+                    };
+
+                    let _ = job.reply.send(response);
+                    continue;
+                }
+            };
+
+            let queued = job.received.elapsed();
+
+            // Time spent waiting for a slot counts towards the timeout
+            let timeout = request
+                .timeout_ms
+                .map(|ms| Duration::from_millis(ms).saturating_sub(queued));
+
+            match pool.submit(
+                request.model.as_ref(),
+                request.dialect,
+                &request.prompt,
+                timeout,
+                || job.is_cancelled(),
+            ) {
+                Ok(Some(ticket)) => {
+                    waiting.insert(ticket, (job, queued));
+                }
+                Ok(None) => queue.push_back(job),
+                Err(e) => {
+                    let _ = job.reply.send(Response::from(Err(e)));
+                }
+            }
+        }
+
+        for (ticket, result) in pool.step() {
+            if let Some((job, queued)) = waiting.remove(&ticket) {
+                let result = result.map(|mut generation| {
+                    generation.timings.queue_ms = queued.as_secs_f64() * 1000.0;
+                    generation
+                });
+
+                let _ = job.reply.send(Response::from(result));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn accept(listener: UnixListener, jobs: Sender<Job>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("failed to accept connection: {e}");
+                continue;
+            }
+        };
+
+        let jobs = jobs.clone();
+
+        thread::spawn(move || {
+            if let Err(e) = handle(stream, jobs) {
+                debug!("connection closed: {e:#}");
+            }
+        });
+    }
+}
+
+fn handle(stream: UnixStream, jobs: Sender<Job>) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+
+    loop {
+        let response = match protocol::read::<Message>(&mut reader) {
+            Ok(Some(message)) => {
+                let (reply, answer) = bounded(1);
+                let cancelled = Arc::new(AtomicBool::new(false));
+
+                jobs.send(Job {
+                    message,
+                    reply,
+                    received: Instant::now(),
+                    cancelled: cancelled.clone(),
+                })?;
+
+                loop {
+                    match answer.recv_timeout(POLL_INTERVAL) {
+                        Ok(response) => break response,
+                        Err(RecvTimeoutError::Timeout) => {
+                            if hung_up(&mut reader)? {
+                                cancelled.store(true, Ordering::Relaxed);
+                                return Ok(());
+                            }
+                        }
+                        Err(RecvTimeoutError::Disconnected) => bail!("server stopped"),
+                    }
+                }
+            }
+            Ok(None) => return Ok(()),
+            Err(e) => Response::from(Err(NaturalError::from(e))),
+        };
+
+        protocol::write(&mut writer, &response)?;
+    }
+}
+
+/// Whether the client closed the connection, without consuming anything it sent
+fn hung_up(reader: &mut BufReader<UnixStream>) -> Result<bool> {
+    reader.get_ref().set_nonblocking(true)?;
+    let buffered = reader.fill_buf().map(|buf| buf.is_empty());
+    reader.get_ref().set_nonblocking(false)?;
+
+    match buffered {
+        Ok(closed) => Ok(closed),
+        Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn binds_over_stale_sockets_only() {
+        let dir = std::env::temp_dir().join(format!("natural-server-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let path = dir.join("private/test.sock");
+
+        let server = Server::bind(&path).unwrap();
+        let mode = path
+            .parent()
+            .unwrap()
+            .metadata()
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        // Live while the listener is open, stale once it is dropped
+        assert!(Server::bind(&path).is_err());
+        drop(server);
+        assert!(Server::bind(&path).is_ok());
+
+        let file = dir.join("private/file");
+        std::fs::write(&file, "keep").unwrap();
+        assert!(Server::bind(&file).is_err());
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "keep");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,115 @@
+//! Inference server loading the model once and sharing it among the postgres instances of a
+//! host, configured with `natural.backend = 'daemon'`, and the `natural` command line.
+//!
+//! Requests are served over a unix domain socket using the
+//! [`protocol`](natural_driver::protocol) of the inference worker. Requests naming another
+//! model than the one the daemon started with load it on demand, see
+//! [`Pool`](natural_driver::pool::Pool), provided it is in one of the `--models-dir`.
+
+use std::path::PathBuf;
+
+use clap::Parser;
+use eyre::Result;
+use llama_cpp_2::llama_backend::LlamaBackend;
+use natural_driver::chat::ChatFormat;
+use natural_driver::dialect::SqlDialect;
+use natural_driver::log::Format;
+use natural_driver::pool::{ModelSpec, Pool, CONTEXT_SIZE};
+use natural_driver::protocol::DAEMON_SOCKET;
+use natural_driver::server::Server;
+use tracing::info;
+
+#[derive(Parser)]
+#[command(
+    name = "daemon",
+    version,
+    about = "Serve a model to natural over a unix socket"
+)]
+struct Args {
+    /// GGUF model file served to requests not naming a model
+    #[arg(long, env = "NATURAL_MODEL")]
+    model: PathBuf,
+    /// Refuse to serve a model file without this hex encoded sha256
+    #[arg(long)]
+    sha256: Option<String>,
+    /// Socket to listen on, its directory is created accessible to the user only
+    #[arg(long, default_value = DAEMON_SOCKET)]
+    socket: PathBuf,
+    /// Directory other models may be requested from, none unless given
+    #[arg(long = "models-dir")]
+    models_dirs: Vec<PathBuf>,
+    /// Number of requests served concurrently per model
+    #[arg(long, default_value_t = 4)]
+    parallel: usize,
+    /// Megabytes loaded models may take up together, 0 for no limit
+    #[arg(long, default_value_t = 0)]
+    memory: u64,
+    /// Context size in tokens per concurrently served request
+    #[arg(long, default_value_t = CONTEXT_SIZE)]
+    context_size: u32,
+    #[arg(long, default_value_t = 4)]
+    threads: i32,
+    /// Layers offloaded to the gpu
+    #[arg(long, default_value_t = 512)]
+    gpu_layers: u32,
+    #[arg(long, default_value_t = ChatFormat::Auto)]
+    chat_format: ChatFormat,
+    #[arg(long, default_value_t = SqlDialect::Postgres)]
+    dialect: SqlDialect,
+    /// Directory evaluated prompt prefixes are persisted in, so restarts start warm
+    #[arg(long)]
+    state_dir: Option<PathBuf>,
+    /// Format of the log written to stderr, text or json
+    #[arg(long, default_value = "text")]
+    log_format: Format,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    natural_driver::log::init_stderr(args.log_format)?;
+
+    let backend = LlamaBackend::init()?;
+
+    let name = args
+        .model
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let mut default = ModelSpec {
+        context_size: args.context_size,
+        chat_format: args.chat_format,
+        sha256: args.sha256.as_deref().map(str::to_lowercase),
+        ..ModelSpec::new(name, &args.model)
+    };
+
+    // Hashing a model takes a while, better once before serving than on every load
+    default.verify()?;
+
+    let mut pool = Pool::new(&backend, default)
+        .with_budget(args.memory * 1024 * 1024)
+        .with_slots(args.parallel)
+        .with_threads(args.threads)
+        .with_gpu_layers(args.gpu_layers)
+        .with_dialect(args.dialect)
+        .with_model_dirs(args.models_dirs.clone());
+
+    if let Some(dir) = &args.state_dir {
+        pool = pool.with_state_dir(dir);
+    }
+
+    pool.preload()?;
+
+    let server = Server::bind(&args.socket)?;
+
+    info!(
+        model = %args.model.display(),
+        socket = %args.socket.display(),
+        parallel = args.parallel,
+        "serving"
+    );
+
+    // Runs until the process is killed, the socket left behind is replaced on the next start
+    server.serve(&mut pool, || true)
+}
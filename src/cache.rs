@@ -0,0 +1,121 @@
+//! Answers generated for questions, shared by all roles.
+//!
+//! Anyone able to write the cache could choose the sql others are given for their questions,
+//! so roles have no access to it. Answers are looked up and stored as the owner of the
+//! extension instead, see [`owner`](crate::owner).
+
+use eyre::Result;
+use natural_driver::pool::ModelSpec;
+use natural_driver::prompt::{fingerprint, normalize_question, Prompt};
+use pgrx::prelude::*;
+
+use crate::owner::{as_owner, writable};
+use crate::{engine, guc};
+
+extension_sql!(
+    r#"
+CREATE TABLE cache (
+    key text PRIMARY KEY,
+    question text NOT NULL,
+    prompt text NOT NULL,
+    model text NOT NULL,
+    sql text NOT NULL,
+    hits bigint NOT NULL DEFAULT 0,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    expires_at timestamptz NOT NULL
+);
+"#,
+    name = "cache"
+);
+
+/// Drop cached answers, either all of them or those of a single question.
+/// Returns the number of dropped answers.
+#[pg_extern]
+fn cache_invalidate(question: default!(Option<&str>, "NULL")) -> i64 {
+    let question = question.map(normalize_question);
+
+    Spi::get_one_with_args::<i64>(
+        "WITH deleted AS (
+            DELETE FROM natural.cache WHERE $1::text IS NULL OR question = $1 RETURNING 1
+         )
+         SELECT count(*) FROM deleted",
+        &[question.into()],
+    )
+    .unwrap_or_else(|e| error!("failed to invalidate cache: {e}"))
+    .unwrap_or_default()
+}
+
+/// Identifies a question asked with a specific prompt (template, schema, examples) and model
+pub struct Key {
+    key: String,
+    question: String,
+    prompt: String,
+    model: String,
+}
+
+impl Key {
+    pub fn new(question: &str, prompt: &Prompt, model: Option<&ModelSpec>) -> Result<Self> {
+        let question = normalize_question(question);
+        let prompt = prompt.cache_key.clone().unwrap_or_default();
+        let model = engine::model_fingerprint(model)?;
+
+        Ok(Self {
+            key: fingerprint(&[&question, &prompt, &model]),
+            question,
+            prompt,
+            model,
+        })
+    }
+}
+
+/// Look up a cached answer, `None` if caching is disabled or the answer expired. Hits are
+/// only counted where the cache can be written.
+pub fn get(key: &Key) -> Result<Option<String>> {
+    if guc::CACHE_TTL.get() == 0 {
+        return Ok(None);
+    }
+
+    let query = if writable() {
+        "UPDATE natural.cache SET hits = hits + 1
+         WHERE key = $1 AND expires_at > now()
+         RETURNING sql"
+    } else {
+        "SELECT sql FROM natural.cache WHERE key = $1 AND expires_at > now()"
+    };
+
+    as_owner(|| {
+        Ok(
+            Spi::get_one_with_args::<String>(query, &[key.key.as_str().into()]).or_else(
+                |e| match e {
+                    spi::Error::InvalidPosition => Ok(None),
+                    e => Err(e),
+                },
+            )?,
+        )
+    })
+}
+
+/// Store an answer, unless caching is disabled or the cache cannot be written
+pub fn put(key: &Key, sql: &str) -> Result<()> {
+    if guc::CACHE_TTL.get() == 0 || !writable() {
+        return Ok(());
+    }
+
+    as_owner(|| {
+        Ok(Spi::run_with_args(
+            "INSERT INTO natural.cache (key, question, prompt, model, sql, expires_at)
+             VALUES ($1, $2, $3, $4, $5, now() + make_interval(secs => $6))
+             ON CONFLICT (key) DO UPDATE
+             SET sql = excluded.sql, hits = 0, created_at = now(),
+                 expires_at = excluded.expires_at",
+            &[
+                key.key.as_str().into(),
+                key.question.as_str().into(),
+                key.prompt.as_str().into(),
+                key.model.as_str().into(),
+                sql.into(),
+                f64::from(guc::CACHE_TTL.get()).into(),
+            ],
+        )?)
+    })
+}
@@ -0,0 +1,145 @@
+//! Inference engine, either hosted by the inference worker or loaded into the backend.
+//!
+//! Loading the model and evaluating the instructions and schema dominate the latency of a
+//! query, so models, their contexts and kv caches are kept between calls. Evaluated prompt
+//! prefixes are persisted below the data directory, so restarted engines start warm.
+
+use std::cell::RefCell;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use eyre::Result;
+use llama_cpp_2::llama_backend::LlamaBackend;
+use natural_driver::dialect::SqlDialect;
+use natural_driver::error::NaturalError;
+use natural_driver::generator::Generation;
+use natural_driver::pool::{ModelSpec, Pool};
+use natural_driver::prompt::Prompt;
+use natural_driver::protocol::Client;
+use pgrx::pg_sys;
+
+use crate::guc;
+
+/// Directory, relative to the data directory, prompt states are persisted in
+pub const STATE_DIR: &str = "natural/cache";
+
+/// Socket of the inference worker, relative to the data directory
+pub const WORKER_SOCKET: &str = "natural/inference.sock";
+
+/// llama.cpp may only be initialised once per process
+static BACKEND: OnceLock<LlamaBackend> = OnceLock::new();
+
+thread_local! {
+    static POOL: RefCell<Option<Pool<'static>>> = const { RefCell::new(None) };
+}
+
+/// Generate sql with `model` on the engine selected by `natural.backend`. Without a model the
+/// engine uses the model of `natural.model_path`.
+///
+/// Generation stops after `natural.generation_timeout` and when the query is cancelled, e.g. by
+/// `pg_cancel_backend` or `statement_timeout`. The cancellation itself is left pending for the
+/// caller to raise.
+pub fn generate(prompt: &Prompt, model: Option<&ModelSpec>) -> Result<Generation, NaturalError> {
+    let timeout = match guc::GENERATION_TIMEOUT.get() {
+        0 => None,
+        ms => Some(Duration::from_millis(ms as u64)),
+    };
+
+    match guc::string(&guc::BACKEND).as_deref() {
+        Some("worker") | None => {
+            Client::connect(WORKER_SOCKET)?.generate(model, prompt, timeout, interrupted)
+        }
+        Some("local") => with_pool(|pool| pool.generate(model, prompt, timeout, interrupted)),
+        // The daemon may have been started for another dialect
+        Some("daemon") => Client::connect(daemon_socket()?)?
+            .with_dialect(SqlDialect::Postgres)
+            .generate(model, prompt, timeout, interrupted),
+        Some(other) => Err(NaturalError::internal(format!(
+            "Unknown natural.backend {other:?}"
+        ))),
+    }
+}
+
+/// Whether the query is about to be cancelled or the backend to terminate
+fn interrupted() -> bool {
+    unsafe { pg_sys::QueryCancelPending != 0 || pg_sys::ProcDiePending != 0 }
+}
+
+/// Run `f` with the model pool of this backend, models are loaded on first use
+fn with_pool<R>(
+    f: impl FnOnce(&mut Pool<'static>) -> Result<R, NaturalError>,
+) -> Result<R, NaturalError> {
+    POOL.with(|cell| {
+        let mut pool = cell.borrow_mut();
+
+        if pool.is_none() {
+            let created = self::pool(1).map_err(|e| NaturalError::ModelLoad {
+                message: format!("{e:#}"),
+            })?;
+
+            *pool = Some(created);
+        }
+
+        f(pool.as_mut().unwrap())
+    })
+}
+
+/// Pool serving `slots` requests per model concurrently. Models are loaded on demand and
+/// unloaded to stay within `natural.model_memory`.
+pub fn pool(slots: usize) -> Result<Pool<'static>> {
+    let backend = match BACKEND.get() {
+        Some(backend) => backend,
+        None => {
+            let backend = LlamaBackend::init()?;
+            BACKEND.get_or_init(|| backend)
+        }
+    };
+
+    // Checked once here, loading the model only compares the size and mtime of its file
+    let mut model = default_model()?;
+    model.verify()?;
+
+    Ok(Pool::new(backend, model)
+        .with_budget(guc::MODEL_MEMORY.get() as u64 * 1024 * 1024)
+        .with_slots(slots)
+        .with_state_dir(STATE_DIR))
+}
+
+/// The model of `natural.model_path`, `natural.chat_format` and `natural.model_sha256`
+pub fn default_model() -> Result<ModelSpec> {
+    let chat_format = guc::string(&guc::CHAT_FORMAT).unwrap_or_default().parse()?;
+
+    Ok(ModelSpec {
+        chat_format,
+        sha256: guc::string(&guc::MODEL_SHA256).map(|hash| hash.to_lowercase()),
+        ..ModelSpec::new("default", model_path()?)
+    })
+}
+
+/// Identifies the model generating with `model` without reading all of its file. A daemon is
+/// asked for the identity of its model, its files need not be readable by postgres.
+pub fn model_fingerprint(model: Option<&ModelSpec>) -> Result<String, NaturalError> {
+    if guc::string(&guc::BACKEND).as_deref() == Some("daemon") {
+        return Client::connect(daemon_socket()?)?.identify(model);
+    }
+
+    match model {
+        Some(model) => model.fingerprint(),
+        None => default_model().map_err(NaturalError::from)?.fingerprint(),
+    }
+}
+
+fn model_path() -> Result<String> {
+    guc::string(&guc::MODEL_PATH).ok_or_else(|| {
+        NaturalError::ModelLoad {
+            message: "no model is configured, set natural.model_path to the path of a GGUF model"
+                .into(),
+        }
+        .into()
+    })
+}
+
+fn daemon_socket() -> Result<String, NaturalError> {
+    guc::string(&guc::DAEMON_SOCKET)
+        .ok_or_else(|| NaturalError::unavailable("natural.daemon_socket is not set"))
+}
@@ -0,0 +1,123 @@
+//! Raises errors with a distinct SQLSTATE per [`NaturalError`], so client applications can
+//! tell e.g. an unanswerable question from an unavailable model.
+
+use natural_driver::error::NaturalError;
+use pgrx::pg_sys::panic::ErrorReport;
+use pgrx::prelude::*;
+
+/// Raise `e` as error, aborting the current transaction
+pub fn raise(e: &eyre::Report) -> ! {
+    let Some(error) = e.downcast_ref::<NaturalError>() else {
+        error!("{e:#}");
+    };
+
+    // Report cancellations the way postgres does, e.g. as statement timeout
+    if *error == NaturalError::Cancelled {
+        pg_sys::check_for_interrupts!();
+    }
+
+    let mut report = ErrorReport::new(code(error), error.to_string(), function_name!());
+
+    if let Some(output) = error.output() {
+        report = report.set_detail(format!("Model output:\n{output}"));
+    }
+
+    if let Some(hint) = error.hint() {
+        report = report.set_hint(hint);
+    }
+
+    report.report(PgLogLevel::ERROR);
+
+    unreachable!("errors do not return")
+}
+
+/// Raise an error about invalid input, aborting the current transaction
+pub fn invalid(code: PgSqlErrorCode, message: impl Into<String>) -> ! {
+    ErrorReport::new(code, message, function_name!()).report(PgLogLevel::ERROR);
+
+    unreachable!("errors do not return")
+}
+
+/// Model output that cannot be used is reported as an exception of the model, class 38, rather
+/// than as an error in the statement or data of the caller. Timeouts share class 57 with
+/// cancellations but keep a code of their own.
+fn code(error: &NaturalError) -> PgSqlErrorCode {
+    match error {
+        NaturalError::ModelLoad { .. } => PgSqlErrorCode::ERRCODE_SYSTEM_ERROR,
+        NaturalError::ContextOverflow { .. } => PgSqlErrorCode::ERRCODE_STATEMENT_TOO_COMPLEX,
+        NaturalError::Decode { .. } => {
+            PgSqlErrorCode::ERRCODE_EXTERNAL_ROUTINE_INVOCATION_EXCEPTION
+        }
+        NaturalError::MissingSqlTag { .. } => PgSqlErrorCode::ERRCODE_EXTERNAL_ROUTINE_EXCEPTION,
+        NaturalError::Parse { .. } => PgSqlErrorCode::ERRCODE_E_R_E_CONTAINING_SQL_NOT_PERMITTED,
+        NaturalError::Validation { .. } => {
+            PgSqlErrorCode::ERRCODE_E_R_E_PROHIBITED_SQL_STATEMENT_ATTEMPTED
+        }
+        NaturalError::Policy { .. } => PgSqlErrorCode::ERRCODE_PROGRAM_LIMIT_EXCEEDED,
+        NaturalError::Timeout { .. } => PgSqlErrorCode::ERRCODE_OPERATOR_INTERVENTION,
+        NaturalError::Cancelled => PgSqlErrorCode::ERRCODE_QUERY_CANCELED,
+        NaturalError::Unavailable { .. } => PgSqlErrorCode::ERRCODE_CONNECTION_FAILURE,
+        NaturalError::Internal { .. } => PgSqlErrorCode::ERRCODE_INTERNAL_ERROR,
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use natural_driver::error::NaturalError;
+    use pgrx::prelude::*;
+
+    use super::code;
+
+    #[pg_test]
+    fn test_sqlstates() {
+        let message = String::new;
+        let output = String::new;
+
+        let errors = [
+            NaturalError::ModelLoad { message: message() },
+            NaturalError::ContextOverflow {
+                tokens: 2,
+                capacity: 1,
+            },
+            NaturalError::Decode { message: message() },
+            NaturalError::MissingSqlTag {
+                tag: "<sql>".to_string(),
+                output: output(),
+            },
+            NaturalError::Parse {
+                message: message(),
+                output: output(),
+            },
+            NaturalError::Validation {
+                message: message(),
+                output: output(),
+            },
+            NaturalError::Policy {
+                message: message(),
+                output: output(),
+            },
+            NaturalError::Timeout { ms: 1 },
+            NaturalError::Cancelled,
+            NaturalError::Unavailable { message: message() },
+            NaturalError::Internal { message: message() },
+        ];
+
+        let codes = errors.each_ref().map(code);
+
+        // Every kind of error can be told apart
+        for (i, a) in codes.iter().enumerate() {
+            for (error, b) in errors.iter().zip(&codes).skip(i + 1) {
+                assert_ne!(a, b, "{:?} shares its SQLSTATE with {error:?}", errors[i]);
+            }
+        }
+
+        // Unusable model output is an exception of the model, class 38
+        let class = |code: PgSqlErrorCode| code as i32 & 0xfff;
+        let external = class(PgSqlErrorCode::ERRCODE_EXTERNAL_ROUTINE_EXCEPTION);
+
+        for error in &errors[3..6] {
+            assert_eq!(class(code(error)), external, "{error:?}");
+        }
+    }
+}
@@ -0,0 +1,47 @@
+//! Explains sql in plain English, the reverse direction of `natural.query`.
+
+use eyre::{eyre, Result};
+use natural_driver::generator::Outcome;
+use natural_driver::prompt::{PromptContext, PromptTemplate};
+use pgrx::prelude::*;
+use sqlparser::dialect::PostgreSqlDialect;
+use sqlparser::parser::Parser;
+
+use crate::{engine, error, models, SCHEMA};
+
+/// Describe what a statement computes, given the schema it runs against
+///
+/// The statement is parsed first, only valid sql is passed to the model. Comparing the
+/// explanation of generated sql with the question it answers is a cheap plausibility check.
+#[pg_extern]
+fn explain_sql(sql: &str) -> String {
+    let statements = Parser::parse_sql(&PostgreSqlDialect {}, sql).unwrap_or_else(|e| {
+        error::invalid(
+            PgSqlErrorCode::ERRCODE_SYNTAX_ERROR,
+            format!("invalid sql: {e}"),
+        )
+    });
+
+    let [statement] = statements.as_slice() else {
+        error::invalid(
+            PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+            format!("expected exactly one statement, got {}", statements.len()),
+        )
+    };
+
+    explain(&statement.to_string()).unwrap_or_else(|e| error::raise(&e))
+}
+
+fn explain(sql: &str) -> Result<String> {
+    let prompt = PromptTemplate::explain().render(&PromptContext {
+        schema: SCHEMA.to_string(),
+        dialect: "The SQL is written for postgres.".to_string(),
+        sql: sql.to_string(),
+        ..Default::default()
+    })?;
+
+    match engine::generate(&prompt, models::model(None)?.as_ref())?.outcome {
+        Outcome::Explanation(explanation) => Ok(explanation),
+        outcome => Err(eyre!("Expected an explanation, got {outcome:?}")),
+    }
+}
@@ -0,0 +1,168 @@
+//! Checks the plan of generated sql, so questions do not turn into queries scanning or
+//! returning far more than intended, e.g. by cross joining two large tables.
+//!
+//! Only queries are accepted, other statements are rejected before they are planned: planning
+//! takes the locks of a statement, and `SELECT INTO` would create a table once run. Queries are
+//! planned by `EXPLAIN (FORMAT JSON)` in a subtransaction, they are never executed.
+//! Queries estimated to return more than `natural.max_rows` are bounded by a `LIMIT`, unless
+//! the question asks for all rows. Sql still exceeding `natural.max_plan_cost` or
+//! `natural.max_rows`, or failing to plan at all, is rejected along with the reason.
+
+use std::ops::ControlFlow;
+use std::panic::AssertUnwindSafe;
+
+use natural_driver::rewrite;
+use pgrx::pg_sys::panic::CaughtError;
+use pgrx::prelude::*;
+use pgrx::PgTryBuilder;
+use sqlparser::ast::{visit_statements, SetExpr, Statement};
+use sqlparser::dialect::PostgreSqlDialect;
+use sqlparser::parser::Parser;
+
+use crate::guc;
+
+pub enum Verdict {
+    /// The sql to use, possibly bounded by a `LIMIT`
+    Accept(String),
+    /// Why the sql was rejected
+    Reject(String),
+}
+
+/// Estimates of the planner for a statement
+struct Plan {
+    cost: f64,
+    rows: f64,
+}
+
+/// Check the plan of sql generated for `question`
+pub fn check(question: &str, sql: &str) -> Verdict {
+    if !is_query(sql) {
+        return Verdict::Reject("The statement is not a query, only SELECT is allowed".to_string());
+    }
+
+    let max_cost = guc::MAX_PLAN_COST.get();
+    let max_rows = guc::MAX_ROWS.get();
+
+    if max_cost <= 0.0 && max_rows <= 0 {
+        return Verdict::Accept(sql.to_string());
+    }
+
+    let mut sql = sql.to_string();
+
+    let mut plan = match explain(&sql) {
+        Ok(plan) => plan,
+        Err(e) => return Verdict::Reject(format!("Planning the query failed: {e}")),
+    };
+
+    if max_rows > 0 && plan.rows > max_rows as f64 && !rewrite::asks_for_all_rows(question) {
+        if let Some(limited) = limit(&sql, max_rows as u64) {
+            match explain(&limited) {
+                Ok(limited_plan) => {
+                    sql = limited;
+                    plan = limited_plan;
+                }
+                Err(e) => return Verdict::Reject(format!("Planning the query failed: {e}")),
+            }
+        }
+    }
+
+    if max_cost > 0.0 && plan.cost > max_cost {
+        return Verdict::Reject(format!(
+            "The estimated cost of {:.0} exceeds natural.max_plan_cost of {max_cost}",
+            plan.cost
+        ));
+    }
+
+    if max_rows > 0 && plan.rows > max_rows as f64 {
+        return Verdict::Reject(format!(
+            "The estimated {:.0} rows exceed natural.max_rows of {max_rows}",
+            plan.rows
+        ));
+    }
+
+    Verdict::Accept(sql)
+}
+
+/// Whether the sql is a single query that only reads, also in its `WITH` clauses
+fn is_query(sql: &str) -> bool {
+    let Ok(statements) = Parser::parse_sql(&PostgreSqlDialect {}, sql) else {
+        return false;
+    };
+
+    let [statement @ Statement::Query(query)] = statements.as_slice() else {
+        return false;
+    };
+
+    if matches!(query.body.as_ref(), SetExpr::Select(select) if select.into.is_some()) {
+        return false;
+    }
+
+    visit_statements(statement, |statement| match statement {
+        Statement::Query(_) => ControlFlow::Continue(()),
+        _ => ControlFlow::Break(()),
+    })
+    .is_continue()
+}
+
+fn limit(sql: &str, rows: u64) -> Option<String> {
+    let mut statements = Parser::parse_sql(&PostgreSqlDialect {}, sql).ok()?;
+    let [statement] = statements.as_mut_slice() else {
+        return None;
+    };
+
+    rewrite::limit(statement, rows).then(|| statement.to_string())
+}
+
+fn explain(sql: &str) -> Result<Plan, String> {
+    let explained =
+        subtransaction(|| Spi::get_one::<pgrx::Json>(&format!("EXPLAIN (FORMAT JSON) {sql}")))?;
+
+    let pgrx::Json(json) = explained
+        .map_err(|e| e.to_string())?
+        .ok_or("EXPLAIN returned no plan")?;
+
+    let plan = &json[0]["Plan"];
+
+    match (plan["Total Cost"].as_f64(), plan["Plan Rows"].as_f64()) {
+        (Some(cost), Some(rows)) => Ok(Plan { cost, rows }),
+        _ => Err(format!("EXPLAIN returned an unexpected plan: {json}")),
+    }
+}
+
+/// Run `f` in a subtransaction, errors it raises roll back the subtransaction and are returned
+/// instead of aborting the transaction
+fn subtransaction<R>(f: impl FnOnce() -> R) -> Result<R, String> {
+    let f = AssertUnwindSafe(f);
+
+    unsafe {
+        let context = pg_sys::CurrentMemoryContext;
+        let owner = pg_sys::CurrentResourceOwner;
+
+        pg_sys::BeginInternalSubTransaction(std::ptr::null());
+        pg_sys::MemoryContextSwitchTo(context);
+
+        PgTryBuilder::new(|| {
+            let result = f();
+
+            pg_sys::ReleaseCurrentSubTransaction();
+            pg_sys::MemoryContextSwitchTo(context);
+            pg_sys::CurrentResourceOwner = owner;
+
+            Ok(result)
+        })
+        .catch_others(|e| {
+            pg_sys::RollbackAndReleaseCurrentSubTransaction();
+            pg_sys::MemoryContextSwitchTo(context);
+            pg_sys::CurrentResourceOwner = owner;
+
+            Err(match e {
+                CaughtError::PostgresError(report)
+                | CaughtError::ErrorReport(report)
+                | CaughtError::RustPanic {
+                    ereport: report, ..
+                } => report.message().to_string(),
+            })
+        })
+        .execute()
+    }
+}
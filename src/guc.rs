@@ -0,0 +1,289 @@
+use std::ffi::CStr;
+
+use pgrx::{GucContext, GucFlags, GucRegistry, GucSetting};
+
+/// Name of the prompt template used when `natural.query` is called without one
+pub static PROMPT_TEMPLATE: GucSetting<Option<&'static CStr>> =
+    GucSetting::<Option<&'static CStr>>::new(Some(c"default"));
+
+/// Where generation happens, `worker`, `local` or `daemon`
+pub static BACKEND: GucSetting<Option<&'static CStr>> =
+    GucSetting::<Option<&'static CStr>>::new(Some(c"worker"));
+
+/// Socket of the daemon serving the model when `natural.backend` is `daemon`
+pub static DAEMON_SOCKET: GucSetting<Option<&'static CStr>> =
+    GucSetting::<Option<&'static CStr>>::new(Some(c"/run/natural/daemon.sock"));
+
+/// Number of requests the inference worker serves concurrently
+pub static PARALLEL: GucSetting<i32> = GucSetting::<i32>::new(4);
+
+/// Path of the GGUF model used for generation
+pub static MODEL_PATH: GucSetting<Option<&'static CStr>> =
+    GucSetting::<Option<&'static CStr>>::new(None);
+
+/// Hex encoded sha256 the file of `natural.model_path` must have, unchecked if unset
+pub static MODEL_SHA256: GucSetting<Option<&'static CStr>> =
+    GucSetting::<Option<&'static CStr>>::new(None);
+
+/// Name of the registered model used when `natural.query` is called without one
+pub static MODEL: GucSetting<Option<&'static CStr>> =
+    GucSetting::<Option<&'static CStr>>::new(None);
+
+/// Comma separated directories `natural.install_model` looks up models in
+pub static MODEL_SOURCES: GucSetting<Option<&'static CStr>> =
+    GucSetting::<Option<&'static CStr>>::new(None);
+
+/// Megabytes of memory loaded models may take up together, 0 disables the limit
+pub static MODEL_MEMORY: GucSetting<i32> = GucSetting::<i32>::new(0);
+
+/// Chat format override, `auto` uses the template embedded in the model
+pub static CHAT_FORMAT: GucSetting<Option<&'static CStr>> =
+    GucSetting::<Option<&'static CStr>>::new(Some(c"auto"));
+
+/// Seconds of inactivity after which a session expires
+pub static SESSION_TIMEOUT: GucSetting<i32> = GucSetting::<i32>::new(3600);
+
+/// Milliseconds after which generation is stopped, 0 disables the timeout
+pub static GENERATION_TIMEOUT: GucSetting<i32> = GucSetting::<i32>::new(60_000);
+
+/// Seconds a generated answer is served from the cache, 0 disables caching
+pub static CACHE_TTL: GucSetting<i32> = GucSetting::<i32>::new(86400);
+
+/// Seconds entries are kept in `natural.history`, 0 disables the history
+pub static HISTORY_RETENTION: GucSetting<i32> = GucSetting::<i32>::new(30 * 86400);
+
+/// Most verbose level of driver and llama.cpp events written to the server log
+pub static LOG_LEVEL: GucSetting<Option<&'static CStr>> =
+    GucSetting::<Option<&'static CStr>>::new(Some(c"warn"));
+
+/// Whether prompts and raw model output are written to the server log
+pub static LOG_PROMPTS: GucSetting<bool> = GucSetting::<bool>::new(false);
+
+/// Rows queries are limited to, 0 disables the limit
+pub static DEFAULT_LIMIT: GucSetting<i32> = GucSetting::<i32>::new(1000);
+
+/// Estimated cost above which generated sql is rejected, 0 disables the check. The default
+/// admits scanning tables of millions of rows, but not joining two of them without an index.
+pub static MAX_PLAN_COST: GucSetting<f64> = GucSetting::<f64>::new(1_000_000.0);
+
+/// Estimated rows above which generated sql is limited or rejected, 0 disables the check. The
+/// default matches `natural.default_limit`, questions asking for more rows have to raise it.
+pub static MAX_ROWS: GucSetting<i32> = GucSetting::<i32>::new(1000);
+
+/// Number of times the model is asked to rewrite rejected sql
+pub static MAX_RETRIES: GucSetting<i32> = GucSetting::<i32>::new(2);
+
+pub fn init() {
+    GucRegistry::define_string_guc(
+        "natural.prompt_template",
+        "Prompt template used to generate sql.",
+        "Name of a template in natural.prompt_templates. Can be set per database or role.",
+        &PROMPT_TEMPLATE,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_string_guc(
+        "natural.backend",
+        "Where sql is generated.",
+        "worker sends requests to the inference worker, local loads the model into the backend, \
+         daemon sends requests to the daemon listening on natural.daemon_socket.",
+        &BACKEND,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_string_guc(
+        "natural.daemon_socket",
+        "Socket of the daemon generating sql.",
+        "Used when natural.backend is daemon. The daemon may serve several postgres instances.",
+        &DAEMON_SOCKET,
+        GucContext::Sighup,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_int_guc(
+        "natural.parallel",
+        "Number of requests the inference worker serves concurrently.",
+        "Each request occupies its own sequence in the kv cache of the model context.",
+        &PARALLEL,
+        1,
+        64,
+        GucContext::Postmaster,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_string_guc(
+        "natural.model_path",
+        "Path of the GGUF model used to generate sql.",
+        "The model is loaded on first use and kept until the process exits. Unset, generating \
+         with the default model fails until it is configured.",
+        &MODEL_PATH,
+        GucContext::Sighup,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_string_guc(
+        "natural.model_sha256",
+        "Expected sha256 of the model at natural.model_path.",
+        "A model file with another hash is refused instead of loaded. Unset skips the check.",
+        &MODEL_SHA256,
+        GucContext::Sighup,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_string_guc(
+        "natural.model",
+        "Registered model used to generate sql.",
+        "Name of a model in natural.models. Unset uses the model of natural.model_path.",
+        &MODEL,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_string_guc(
+        "natural.model_sources",
+        "Directories natural.install_model looks up models in.",
+        "Comma separated Hugging Face hub caches or mirrors laid out as <repo>/<file>. Unset uses \
+         the hub cache of the server environment.",
+        &MODEL_SOURCES,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_int_guc(
+        "natural.model_memory",
+        "Memory loaded models may take up together.",
+        "Least recently used models are unloaded to load another one. 0 disables the limit.",
+        &MODEL_MEMORY,
+        0,
+        i32::MAX,
+        GucContext::Postmaster,
+        GucFlags::UNIT_MB,
+    );
+
+    GucRegistry::define_string_guc(
+        "natural.chat_format",
+        "Chat format used to frame prompts.",
+        "One of auto, mistral, chatml, llama3 or plain. auto uses the chat template of the model.",
+        &CHAT_FORMAT,
+        GucContext::Sighup,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_int_guc(
+        "natural.session_timeout",
+        "Seconds of inactivity after which a session expires.",
+        "Sessions keep the history of follow-up questions started with natural.session_start().",
+        &SESSION_TIMEOUT,
+        1,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::UNIT_S,
+    );
+
+    GucRegistry::define_int_guc(
+        "natural.generation_timeout",
+        "Milliseconds after which generating sql is stopped.",
+        "Applies on top of statement_timeout. 0 disables the timeout.",
+        &GENERATION_TIMEOUT,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::UNIT_MS,
+    );
+
+    GucRegistry::define_int_guc(
+        "natural.cache_ttl",
+        "Seconds a generated answer is served from the cache.",
+        "Answers are cached per question, prompt and model. 0 disables the cache.",
+        &CACHE_TTL,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::UNIT_S,
+    );
+
+    GucRegistry::define_int_guc(
+        "natural.history_retention",
+        "Seconds questions are kept in natural.history.",
+        "Older entries are deleted as new ones are recorded. 0 disables the history.",
+        &HISTORY_RETENTION,
+        0,
+        i32::MAX,
+        GucContext::Sighup,
+        GucFlags::UNIT_S,
+    );
+
+    GucRegistry::define_int_guc(
+        "natural.default_limit",
+        "Number of rows generated queries are limited to.",
+        "Applies unless a query has a lower LIMIT or only computes aggregates, or the question \
+         asks for all rows. 0 disables it.",
+        &DEFAULT_LIMIT,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_float_guc(
+        "natural.max_plan_cost",
+        "Estimated plan cost above which generated sql is rejected.",
+        "Compared with the total cost of EXPLAIN, in units of seq_page_cost. 0 disables the check.",
+        &MAX_PLAN_COST,
+        0.0,
+        f64::MAX,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_int_guc(
+        "natural.max_rows",
+        "Estimated number of rows above which generated sql is limited or rejected.",
+        "A LIMIT is added unless the question asks for all rows, which is rejected instead. 0 \
+         disables the check.",
+        &MAX_ROWS,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_int_guc(
+        "natural.max_retries",
+        "Number of times the model is asked to rewrite rejected sql.",
+        "Rejected sql is passed back to the model along with the reason it was rejected.",
+        &MAX_RETRIES,
+        0,
+        10,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_string_guc(
+        "natural.log_level",
+        "Most verbose level of natural events written to the server log.",
+        "One of off, error, warn, info, debug or trace. Covers the driver and llama.cpp.",
+        &LOG_LEVEL,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_bool_guc(
+        "natural.log_prompts",
+        "Write prompts and raw model output to the server log.",
+        "Prompts contain questions and schema details, only enable this for debugging.",
+        &LOG_PROMPTS,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+}
+
+/// Read a string guc, treating unset and empty values alike
+pub fn string(setting: &GucSetting<Option<&'static CStr>>) -> Option<String> {
+    setting
+        .get()
+        .map(|value| value.to_string_lossy().into_owned())
+        .filter(|value| !value.is_empty())
+}
@@ -0,0 +1,181 @@
+//! Audit log of the questions asked through `natural.query`.
+//!
+//! Entries are rows of `natural.history`, written as the owner of the extension so roles can
+//! neither read nor forge them, see [`owner`](crate::owner). They are part of the transaction
+//! of the call: a call that fails raises an error and its entry is rolled back along with the
+//! rest of the transaction, the error remains in the server log. Entries older than
+//! `natural.history_retention` are deleted as new ones are recorded.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use eyre::Result;
+use natural_driver::generator::Timings;
+use pgrx::prelude::*;
+
+use crate::guc;
+use crate::owner::{as_owner, writable};
+
+extension_sql!(
+    r#"
+CREATE TABLE history (
+    id bigserial PRIMARY KEY,
+    timestamp timestamptz NOT NULL,
+    role text NOT NULL,
+    database text NOT NULL,
+    question text NOT NULL,
+    -- Fingerprints of the schema the question was asked against and of the rendered prompt
+    schema_version text,
+    prompt_hash text,
+    -- Raw output of the model
+    output text,
+    sql text,
+    status text NOT NULL,
+    retries int NOT NULL,
+    tokens_in bigint NOT NULL,
+    tokens_out bigint NOT NULL,
+    latency_ms float8 NOT NULL,
+    -- Whether the sql was run, see natural.mark_executed()
+    executed boolean NOT NULL DEFAULT false,
+    error text
+);
+
+CREATE INDEX ON history (timestamp);
+"#,
+    name = "history"
+);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Status {
+    /// The model answered with valid sql
+    #[default]
+    Generated,
+    /// The answer was served from the cache
+    Cached,
+    /// The model asked a clarifying question instead of answering
+    Clarification,
+    Failed,
+}
+
+impl Status {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Generated => "generated",
+            Self::Cached => "cached",
+            Self::Clarification => "clarification",
+            Self::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Entry {
+    /// Seconds since the unix epoch
+    pub timestamp: f64,
+    pub role: String,
+    pub database: String,
+    pub question: String,
+    /// Fingerprint of the schema the question was asked against
+    pub schema_version: Option<String>,
+    /// Fingerprint of the rendered prompt
+    pub prompt_hash: Option<String>,
+    /// Raw output of the model
+    pub output: Option<String>,
+    pub sql: Option<String>,
+    pub status: Status,
+    pub retries: i32,
+    pub tokens_in: i64,
+    /// Prompt tokens reused from the kv cache
+    pub cached_tokens: i64,
+    pub tokens_out: i64,
+    pub latency_ms: f64,
+    pub timings: Option<Timings>,
+    pub error: Option<String>,
+}
+
+impl Entry {
+    /// Start an entry for a question asked by the current role
+    pub fn new(question: &str) -> Result<Self> {
+        let (role, database) =
+            Spi::get_two::<String, String>("SELECT current_user::text, current_database()::text")?;
+
+        Ok(Self {
+            timestamp: now(),
+            role: role.unwrap_or_default(),
+            database: database.unwrap_or_default(),
+            question: question.to_string(),
+            ..Default::default()
+        })
+    }
+}
+
+/// Insert an entry and drop expired ones, returns its id. `None` if the history is disabled
+/// or the transaction cannot write, e.g. on a standby.
+pub fn record(entry: &Entry) -> Result<Option<i64>> {
+    let retention = guc::HISTORY_RETENTION.get();
+
+    if retention == 0 || !writable() {
+        return Ok(None);
+    }
+
+    as_owner(|| {
+        Spi::run_with_args(
+            "DELETE FROM natural.history WHERE timestamp < now() - make_interval(secs => $1)",
+            &[f64::from(retention).into()],
+        )?;
+
+        Ok(Spi::get_one_with_args::<i64>(
+            "INSERT INTO natural.history
+                 (timestamp, role, database, question, schema_version, prompt_hash, output, sql,
+                  status, retries, tokens_in, tokens_out, latency_ms, error)
+             VALUES (to_timestamp($1), $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14)
+             RETURNING id",
+            &[
+                entry.timestamp.into(),
+                entry.role.as_str().into(),
+                entry.database.as_str().into(),
+                entry.question.as_str().into(),
+                entry.schema_version.as_deref().into(),
+                entry.prompt_hash.as_deref().into(),
+                entry.output.as_deref().into(),
+                entry.sql.as_deref().into(),
+                entry.status.as_str().into(),
+                entry.retries.into(),
+                entry.tokens_in.into(),
+                entry.tokens_out.into(),
+                entry.latency_ms.into(),
+                entry.error.as_deref().into(),
+            ],
+        )?)
+    })
+}
+
+/// Record that the sql of a history entry was run, `history_id` is returned by
+/// `natural.query_detailed`. Only entries of the current role can be marked, returns whether
+/// the entry was found.
+#[pg_extern]
+fn mark_executed(history_id: i64) -> bool {
+    let role = Spi::get_one::<String>("SELECT current_user::text")
+        .unwrap_or_else(|e| error!("failed to mark history: {e}"));
+
+    as_owner(|| {
+        Ok(Spi::get_one_with_args::<bool>(
+            "UPDATE natural.history SET executed = true WHERE id = $1 AND role = $2
+             RETURNING true",
+            &[history_id.into(), role.into()],
+        )
+        .or_else(|e| match e {
+            spi::Error::InvalidPosition => Ok(None),
+            e => Err(e),
+        })?)
+    })
+    .unwrap_or_else(|e| error!("failed to mark history: {e:#}"))
+    .unwrap_or_default()
+}
+
+/// Seconds since the unix epoch
+pub fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
@@ -1,38 +1,338 @@
+use std::time::Instant;
+
+use natural_driver::dialect::SqlDialect;
+use natural_driver::error::NaturalError;
+use natural_driver::extract::Clarification;
+use natural_driver::generator::{Outcome, Timings};
+use natural_driver::inspect;
 use pgrx::bgworkers::*;
 use pgrx::prelude::*;
+use serde::Serialize;
+use sqlparser::dialect::PostgreSqlDialect;
+use sqlparser::parser::Parser;
+
+mod cache;
+mod engine;
+mod error;
+mod explain;
+mod guard;
+mod guc;
+mod history;
+mod logging;
+mod models;
+mod owner;
+mod prompt;
+mod rewrite;
+mod session;
+mod stats;
+mod worker;
 
 ::pgrx::pg_module_magic!();
 
+// Tables and views grant access to what roles need themselves
+extension_sql!(
+    "GRANT USAGE ON SCHEMA natural TO PUBLIC;\n",
+    name = "schema_usage"
+);
+
+/// Schema questions are answered against, until it is loaded from the database
+const SCHEMA: &str = "CREATE TABLE users (id INT PRIMARY KEY, name TEXT, email TEXT);\n CREATE TABLE orders (id SERIAL PRIMARY KEY, product TEXT NOT NULL);";
+
 // Legacy / alternative candle-based driver
 // mod driver;
 
 /// Driver invocation through an SQL function against a predefined SQL schema
 ///
+/// Returns the generated sql and whether it was served from the cache. Questions asked
+/// within a session bypass the cache, their answers depend on the previous turns. Calls are
+/// recorded in `natural.history`, unless they fail and their transaction is rolled back.
+///
+/// Instead of guessing the meaning of an ambiguous question, the model may ask back: `sql`
+/// is null and `clarification` holds its question along with the `options` it suggests. The
+/// answer is passed as the next question of the same session.
+///
+/// Generated sql is limited to `natural.default_limit` rows, unless the question asks for all
+/// of them, and its tables are qualified with their schema. It is planned before it is
+/// returned and checked against `natural.max_plan_cost` and `natural.max_rows`. Rejected sql
+/// is passed back to the model to be rewritten, up to `natural.max_retries` times.
+///
+/// The sql is generated with `model`, a name registered with `natural.register_model`, or
+/// `natural.model`. Without either the model of `natural.model_path` is used.
+///
 /// Missing steps are:
 /// 1. Dynamic schema loading & IR for the model
 /// 2. Execution of generated SQL
 #[pg_extern]
-fn query(query: &str) -> String {
-    use natural_driver::generator::SqlGenerator;
-
-    use llama_cpp_2::context::params::LlamaContextParams;
-    use llama_cpp_2::llama_backend::LlamaBackend;
-    use llama_cpp_2::model::params::LlamaModelParams;
-    use llama_cpp_2::model::LlamaModel;
-
-    let backend = LlamaBackend::init().unwrap();
-    let model_params = LlamaModelParams::default().with_n_gpu_layers(512);
-    let model =
-        LlamaModel::load_from_file(&backend, "/home/mara/Workspace/mistral.gguf", &model_params)
-            .unwrap();
-    let ctx_params = LlamaContextParams::default().with_n_threads(4);
-    let context = model.new_context(&backend, ctx_params).unwrap();
+fn query(
+    question: &str,
+    template: default!(Option<&str>, "NULL"),
+    session: default!(Option<pgrx::Uuid>, "NULL"),
+    model: default!(Option<&str>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(sql, Option<String>),
+        name!(cached, bool),
+        name!(clarification, Option<String>),
+        name!(options, Option<Vec<String>>),
+    ),
+> {
+    let answer = run(question, template, session, model);
+    let (clarification, options) = answer
+        .clarification
+        .map(|c| (c.question, c.options))
+        .unzip();
+
+    TableIterator::once((answer.sql, answer.cached, clarification, options))
+}
+
+/// Like `natural.query`, but also returns how the sql came about as a jsonb object
+///
+/// Next to `sql`, `cached` and `clarification` it holds the `explanation` the model gave, the
+/// `tables` the sql references, the model's `confidence` (geometric mean of the token
+/// probabilities, between 0 and 1), the `retries` used and the `tokens` and `timings` of the
+/// generation. Values that are unknown for cached answers are null. `history_id` identifies
+/// the call in `natural.history`, pass it to `natural.mark_executed` once the sql was run.
+#[pg_extern]
+fn query_detailed(
+    question: &str,
+    template: default!(Option<&str>, "NULL"),
+    session: default!(Option<pgrx::Uuid>, "NULL"),
+    model: default!(Option<&str>, "NULL"),
+) -> pgrx::JsonB {
+    let answer = run(question, template, session, model);
+
+    pgrx::JsonB(
+        serde_json::to_value(&answer).unwrap_or_else(|e| error!("failed to serialize: {e}")),
+    )
+}
+
+/// Sql answering a question, along with what is known about how it came about
+#[derive(Serialize)]
+struct Answer {
+    sql: Option<String>,
+    cached: bool,
+    clarification: Option<Clarification>,
+    explanation: Option<String>,
+    tables: Vec<String>,
+    confidence: Option<f64>,
+    retries: i32,
+    tokens: Tokens,
+    timings: Option<Timings>,
+    /// Entry of the call in `natural.history`, see `natural.mark_executed`
+    history_id: Option<i64>,
+}
+
+#[derive(Default, Serialize)]
+struct Tokens {
+    prompt: i64,
+    cached: i64,
+    generated: i64,
+}
+
+/// Answer a question, recording the call in the statistics and history. Failures are raised.
+fn run(
+    question: &str,
+    template: Option<&str>,
+    session: Option<pgrx::Uuid>,
+    model: Option<&str>,
+) -> Answer {
+    let started = Instant::now();
+    let _tracked = stats::Tracked::start(question);
+
+    let mut entry =
+        history::Entry::new(question).unwrap_or_else(|e| error!("failed to start history: {e}"));
+
+    let result = answer(question, template, session, model, &mut entry);
+
+    entry.latency_ms = started.elapsed().as_secs_f64() * 1000.0;
 
-    let mut generator = SqlGenerator::new(context).unwrap();
+    if let Err(e) = &result {
+        entry.status = history::Status::Failed;
+        entry.error = Some(format!("{e:#}"));
 
-    let schema = "CREATE TABLE users (id INT PRIMARY KEY, name TEXT, email TEXT);\n CREATE TABLE orders (id SERIAL PRIMARY KEY, product TEXT NOT NULL);";
+        if let Some(output) = e
+            .downcast_ref::<NaturalError>()
+            .and_then(NaturalError::output)
+        {
+            entry.output = Some(output.to_string());
+        }
+    }
+
+    stats::record(&entry);
 
-    generator.generate(query, schema).unwrap().to_string()
+    let history_id = history::record(&entry).unwrap_or_else(|e| {
+        warning!("failed to record history: {e:#}");
+        None
+    });
+
+    Answer {
+        history_id,
+        ..result.unwrap_or_else(|e| error::raise(&e))
+    }
+}
+
+/// Generate or look up the sql for a question, filling in the history entry along the way
+fn answer(
+    question: &str,
+    template: Option<&str>,
+    session: Option<pgrx::Uuid>,
+    model: Option<&str>,
+    entry: &mut history::Entry,
+) -> eyre::Result<Answer> {
+    use natural_driver::prompt::{fingerprint, PromptContext, Turn};
+
+    use crate::guard::Verdict;
+
+    entry.schema_version = Some(fingerprint(&[SCHEMA]));
+
+    let template = prompt::template(template)?;
+    let model = models::model(model)?;
+
+    let mut context = PromptContext {
+        schema: SCHEMA.to_string(),
+        question: question.to_string(),
+        dialect: SqlDialect::Postgres.notes(),
+        examples: prompt::examples(template.name())?,
+        history: session
+            .map(session::history)
+            .transpose()?
+            .unwrap_or_default(),
+        ..Default::default()
+    };
+
+    let mut prompt = template.render(&context)?;
+
+    entry.prompt_hash = Some(fingerprint(
+        &prompt
+            .messages
+            .iter()
+            .map(|message| message.content.as_str())
+            .collect::<Vec<_>>(),
+    ));
+
+    let key = cache::Key::new(question, &prompt, model.as_ref())?;
+
+    if session.is_none() {
+        let cached = cache::get(&key)?
+            .map(|sql| rewrite::apply(question, &sql))
+            .transpose()?;
+
+        // The limits may have changed since the sql was cached
+        if let Some(Verdict::Accept(sql)) = cached.map(|sql| guard::check(question, &sql)) {
+            entry.status = history::Status::Cached;
+            entry.sql = Some(sql.clone());
+
+            return Ok(Answer {
+                tables: tables(&sql),
+                sql: Some(sql),
+                cached: true,
+                clarification: None,
+                explanation: None,
+                confidence: None,
+                retries: 0,
+                tokens: Tokens::default(),
+                timings: None,
+                history_id: None,
+            });
+        }
+    }
+
+    let generation = loop {
+        let mut generation = engine::generate(&prompt, model.as_ref())?;
+
+        entry.output = Some(generation.output.clone());
+        entry.tokens_in += generation.prompt_tokens as i64;
+        entry.cached_tokens += generation.cached_tokens as i64;
+        entry.tokens_out += generation.generated_tokens as i64;
+        *entry.timings.get_or_insert_default() += generation.timings;
+
+        let Outcome::Sql { sql, tables, .. } = &mut generation.outcome else {
+            break generation;
+        };
+
+        *sql = rewrite::apply(question, sql)?;
+        *tables = self::tables(sql);
+
+        match guard::check(question, sql) {
+            Verdict::Accept(checked) => {
+                *sql = checked;
+                break generation;
+            }
+            Verdict::Reject(reason) if entry.retries < guc::MAX_RETRIES.get() => {
+                entry.retries += 1;
+
+                // Pass the rejected sql back to the model like a previous turn of a session
+                context.history.push(Turn {
+                    question: question.to_string(),
+                    sql: sql.clone(),
+                    summary: Some(format!("Rejected. {reason}, rewrite the query.")),
+                    ..Default::default()
+                });
+
+                prompt = template.render(&context)?;
+            }
+            Verdict::Reject(reason) => {
+                return Err(NaturalError::Policy {
+                    message: reason,
+                    output: generation.output,
+                }
+                .into());
+            }
+        }
+    };
+
+    entry.sql = generation.outcome.sql().map(str::to_string);
+
+    match (session, &generation.outcome) {
+        (Some(session), outcome) => session::record(session, question, outcome)?,
+        (None, Outcome::Sql { sql, .. }) => cache::put(&key, sql)?,
+        // Only a session can answer a clarifying question, caching it would just repeat it
+        (None, Outcome::Clarification(_) | Outcome::Explanation(_)) => {}
+    }
+
+    let (sql, explanation, tables, clarification) = match generation.outcome {
+        Outcome::Sql {
+            sql,
+            explanation,
+            tables,
+        } => (Some(sql), explanation, tables, None),
+        Outcome::Clarification(clarification) => {
+            entry.status = history::Status::Clarification;
+            (None, None, vec![], Some(clarification))
+        }
+        Outcome::Explanation(_) => {
+            return Err(NaturalError::Validation {
+                message: format!("template {:?} does not generate sql", template.name()),
+                output: generation.output,
+            }
+            .into());
+        }
+    };
+
+    Ok(Answer {
+        sql,
+        cached: false,
+        clarification,
+        explanation,
+        tables,
+        confidence: Some(generation.confidence),
+        retries: entry.retries,
+        tokens: Tokens {
+            prompt: entry.tokens_in,
+            cached: entry.cached_tokens,
+            generated: entry.tokens_out,
+        },
+        timings: entry.timings,
+        history_id: None,
+    })
+}
+
+/// Tables referenced by previously generated sql
+fn tables(sql: &str) -> Vec<String> {
+    Parser::parse_sql(&PostgreSqlDialect {}, sql)
+        .map(|statements| statements.iter().flat_map(inspect::tables).collect())
+        .unwrap_or_default()
 }
 
 /// Example on how to use the server programming interface to query postgres
@@ -65,43 +365,164 @@ fn spi_return_query() -> Result<
 
 #[pg_guard]
 pub extern "C-unwind" fn _PG_init() {
+    guc::init();
+    logging::init();
+    stats::init();
+
     BackgroundWorkerBuilder::new("Natural Inference Worker")
         .set_function("natural_inference_worker")
         .set_library("natural")
-        .set_argument(42i32.into_datum())
-        .enable_spi_access()
         .load();
 }
 
-/// Example BG worker creation in PGRX for further usage
-#[pg_guard]
-#[no_mangle]
-pub extern "C-unwind" fn natural_inference_worker(arg: pg_sys::Datum) {
-    let arg = unsafe { i32::from_polymorphic_datum(arg, false, pg_sys::INT4OID) };
-
-    BackgroundWorker::attach_signal_handlers(SignalWakeFlags::SIGHUP | SignalWakeFlags::SIGTERM);
-
-    BackgroundWorker::connect_worker_to_spi(Some("postgres"), None);
-
-    log!(
-        "Hello from inside the {} natural inference worker!  Argument value={}",
-        BackgroundWorker::get_name(),
-        arg.unwrap()
-    );
-
-    log!(
-        "Goodbye from inside of the {} natural inference worker! ",
-        BackgroundWorker::get_name()
-    );
-}
-
 #[cfg(any(test, feature = "pg_test"))]
 #[pg_schema]
 mod tests {
+    use std::io::BufReader;
+    use std::os::unix::net::UnixListener;
+
+    use natural_driver::generator::{Generation, Outcome};
+    use natural_driver::protocol::{self, Message, Response};
     use pgrx::prelude::*;
 
+    /// Socket of the daemon answering the tests, see `postgresql_conf_options`
+    const DAEMON_SOCKET: &str = "/tmp/natural-test/daemon.sock";
+
     #[pg_test]
     fn test_hello_natural() {}
+
+    #[pg_test]
+    fn test_query_as_unprivileged_role() {
+        daemon("SELECT 1");
+
+        Spi::run("CREATE ROLE natural_user").unwrap();
+        Spi::run("SET natural.backend = 'daemon'").unwrap();
+        Spi::run("SET ROLE natural_user").unwrap();
+
+        // Generated first, then answered from the cache
+        for cached in [false, true] {
+            let answer = Spi::get_two::<String, bool>(
+                "SELECT sql, cached FROM natural.query('how many users are there')",
+            )
+            .unwrap();
+
+            assert!(answer.0.unwrap().starts_with("SELECT 1"));
+            assert_eq!(answer.1, Some(cached));
+        }
+
+        let session = Spi::get_one::<pgrx::Uuid>("SELECT natural.session_start()")
+            .unwrap()
+            .unwrap();
+
+        Spi::run_with_args(
+            "SELECT natural.query('how many orders are there', session => $1)",
+            &[session.into()],
+        )
+        .unwrap();
+        Spi::run_with_args(
+            "SELECT natural.session_note($1, 'one row')",
+            &[session.into()],
+        )
+        .unwrap();
+
+        let ended =
+            Spi::get_one_with_args::<bool>("SELECT natural.session_end($1)", &[session.into()]);
+        assert_eq!(ended.unwrap(), Some(true));
+
+        let id = Spi::get_one::<i64>(
+            "SELECT (natural.query_detailed('how many users are there')->>'history_id')::bigint",
+        )
+        .unwrap()
+        .unwrap();
+
+        let marked =
+            Spi::get_one_with_args::<bool>("SELECT natural.mark_executed($1)", &[id.into()]);
+        assert_eq!(marked.unwrap(), Some(true));
+
+        Spi::run("RESET ROLE").unwrap();
+
+        let entry = Spi::get_two_with_args::<String, bool>(
+            "SELECT role, executed FROM natural.history WHERE id = $1",
+            &[id.into()],
+        )
+        .unwrap();
+        assert_eq!(entry, (Some("natural_user".to_string()), Some(true)));
+    }
+
+    #[pg_test(
+        error = "generated sql violates policy: The statement is not a query, only SELECT is allowed"
+    )]
+    fn test_query_rejects_delete() {
+        daemon("DELETE FROM pg_class");
+
+        Spi::run("SET natural.backend = 'daemon'").unwrap();
+        Spi::run("SELECT natural.query('forget all tables')").unwrap();
+    }
+
+    #[pg_test(
+        error = "generated sql violates policy: The statement is not a query, only SELECT is allowed"
+    )]
+    fn test_query_rejects_insert() {
+        daemon("INSERT INTO pg_class DEFAULT VALUES");
+
+        Spi::run("SET natural.backend = 'daemon'").unwrap();
+        Spi::run("SELECT natural.query('add a table')").unwrap();
+    }
+
+    #[pg_test]
+    fn test_query_read_only() {
+        daemon("SELECT 1");
+
+        Spi::run("SET natural.backend = 'daemon'").unwrap();
+        Spi::run("SET transaction_read_only = on").unwrap();
+
+        // Answered, but not cached
+        for _ in 0..2 {
+            let cached = Spi::get_one::<bool>(
+                "SELECT cached FROM natural.query('how many users are there')",
+            );
+            assert_eq!(cached.unwrap(), Some(false));
+        }
+    }
+
+    /// Serve `sql` as the answer to every question
+    fn daemon(sql: &'static str) {
+        let path = std::path::Path::new(DAEMON_SOCKET);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let _ = std::fs::remove_file(path);
+
+        let listener = UnixListener::bind(path).unwrap();
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut writer = stream;
+
+                while let Ok(Some(message)) = protocol::read::<Message>(&mut reader) {
+                    let response = match message {
+                        Message::Identify { .. } => Response::Identity {
+                            fingerprint: "test".to_string(),
+                        },
+                        Message::Generate(_) => Response::Ok(Generation {
+                            outcome: Outcome::Sql {
+                                sql: sql.to_string(),
+                                explanation: None,
+                                tables: vec![],
+                            },
+                            output: format!("<sql>{sql}</sql>"),
+                            prompt_tokens: 1,
+                            cached_tokens: 0,
+                            generated_tokens: 1,
+                            timings: Default::default(),
+                            confidence: 1.0,
+                        }),
+                    };
+
+                    let _ = protocol::write(&mut writer, &response);
+                }
+            }
+        });
+    }
 }
 
 #[cfg(test)]
@@ -110,6 +531,6 @@ pub mod pg_test {
 
     #[must_use]
     pub fn postgresql_conf_options() -> Vec<&'static str> {
-        vec![]
+        vec!["natural.daemon_socket = '/tmp/natural-test/daemon.sock'"]
     }
 }
@@ -0,0 +1,159 @@
+//! Forwards `tracing` events of the driver and llama.cpp to the postgres log.
+//!
+//! Events pass when their level is within `natural.log_level`, prompts and model output only
+//! with `natural.log_prompts`. postgres may only be called from the thread it runs on, events
+//! of other threads are held back until that thread logs or [`flush`] is called, and filtered
+//! by the settings that thread saw last.
+
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::thread::{self, ThreadId};
+
+use natural_driver::log::{capture_llama_logs, PROMPTS};
+use pgrx::prelude::*;
+use tracing::field::{Field, Visit};
+use tracing::level_filters::LevelFilter;
+use tracing::subscriber::Interest;
+use tracing::{Event, Level, Metadata, Subscriber};
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::{Layer, Registry};
+
+use crate::guc;
+
+/// Thread postgres runs on
+static MAIN: OnceLock<ThreadId> = OnceLock::new();
+
+/// Events of other threads waiting to be logged
+static PENDING: Mutex<Vec<(Level, String)>> = Mutex::new(vec![]);
+
+/// Settings as last read on the main thread, see [`level`]
+static LEVEL: AtomicUsize = AtomicUsize::new(2);
+static PROMPTS_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Install the postgres layer as global subscriber, must be called from `_PG_init`
+pub fn init() {
+    MAIN.get_or_init(|| thread::current().id());
+
+    if tracing::subscriber::set_global_default(Registry::default().with(PostgresLayer)).is_ok() {
+        capture_llama_logs();
+    }
+}
+
+/// Log the events other threads produced since the last call
+pub fn flush() {
+    if !is_main() {
+        return;
+    }
+
+    let pending = std::mem::take(&mut *PENDING.lock().unwrap_or_else(|e| e.into_inner()));
+
+    for (level, message) in pending {
+        report(level, &message);
+    }
+}
+
+struct PostgresLayer;
+
+impl<S: Subscriber> Layer<S> for PostgresLayer {
+    fn register_callsite(&self, _: &'static Metadata<'static>) -> Interest {
+        // The settings may change at any time, so decide per event
+        Interest::sometimes()
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>, _: Context<'_, S>) -> bool {
+        if is_main() {
+            refresh();
+        }
+
+        if metadata.target() == PROMPTS {
+            return PROMPTS_ENABLED.load(Ordering::Relaxed);
+        }
+
+        *metadata.level() <= level()
+    }
+
+    fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+        let mut message = Message::default();
+        event.record(&mut message);
+
+        let level = *event.metadata().level();
+        let message = format!(
+            "{}: {}{}",
+            event.metadata().target(),
+            message.message,
+            message.fields
+        );
+
+        if !is_main() {
+            PENDING
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push((level, message));
+            return;
+        }
+
+        flush();
+        report(level, &message);
+    }
+}
+
+/// Errors of the driver never abort the transaction, they are reported by the caller
+fn report(level: Level, message: &str) {
+    match level {
+        Level::ERROR | Level::WARN => warning!("{message}"),
+        _ => log!("{message}"),
+    }
+}
+
+/// Read the settings, they must not be read on other threads as postgres may reassign them
+pub fn refresh() {
+    let level = guc::string(&guc::LOG_LEVEL)
+        .and_then(|level| level.parse::<LevelFilter>().ok())
+        .unwrap_or(LevelFilter::WARN);
+
+    LEVEL.store(encode(level), Ordering::Relaxed);
+    PROMPTS_ENABLED.store(guc::LOG_PROMPTS.get(), Ordering::Relaxed);
+}
+
+fn level() -> LevelFilter {
+    match LEVEL.load(Ordering::Relaxed) {
+        0 => LevelFilter::OFF,
+        1 => LevelFilter::ERROR,
+        2 => LevelFilter::WARN,
+        3 => LevelFilter::INFO,
+        4 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    }
+}
+
+fn encode(level: LevelFilter) -> usize {
+    match level.into_level() {
+        None => 0,
+        Some(Level::ERROR) => 1,
+        Some(Level::WARN) => 2,
+        Some(Level::INFO) => 3,
+        Some(Level::DEBUG) => 4,
+        Some(_) => 5,
+    }
+}
+
+fn is_main() -> bool {
+    MAIN.get() == Some(&thread::current().id())
+}
+
+/// Event fields rendered as `message key=value ...`
+#[derive(Default)]
+struct Message {
+    message: String,
+    fields: String,
+}
+
+impl Visit for Message {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let _ = match field.name() {
+            "message" => write!(self.message, "{value:?}"),
+            name => write!(self.fields, " {name}={value:?}"),
+        };
+    }
+}
@@ -0,0 +1,252 @@
+//! Catalog of the models `natural.query` can generate with, besides the one of
+//! `natural.model_path`.
+//!
+//! Registered models are loaded by the engine on their first request, see
+//! [`Pool`](natural_driver::pool::Pool). The sha256 of a file is recorded when it is registered
+//! and checked before it is loaded, so a file swapped on disk is refused rather than silently
+//! changing the sql generated.
+//!
+//! Models of the Hugging Face hub are installed below the data directory from the directories
+//! of `natural.model_sources`, see [`hub`].
+
+use std::path::{Path, PathBuf};
+
+use eyre::{eyre, Result, WrapErr};
+use natural_driver::chat::ChatFormat;
+use natural_driver::generator::Sampling;
+use natural_driver::gguf::{self, ModelInfo};
+use natural_driver::hub;
+use natural_driver::pool::ModelSpec;
+use pgrx::prelude::*;
+
+use crate::{engine, error, guc};
+
+/// Directory, relative to the data directory, models are installed in
+pub const MODELS_DIR: &str = "natural/models";
+
+extension_sql!(
+    r#"
+CREATE TABLE models (
+    name text PRIMARY KEY,
+    path text NOT NULL,
+    -- Tokens per concurrently served request
+    context_size int NOT NULL DEFAULT 4096 CHECK (context_size > 0),
+    chat_format text NOT NULL DEFAULT 'auto',
+    -- Sampling parameters: temperature, top_k, top_p, seed and max_tokens
+    params jsonb NOT NULL DEFAULT '{}',
+    -- Hex encoded sha256 of the file when it was registered
+    sha256 text NOT NULL
+);
+
+-- Read by natural.query, changed by registering models
+GRANT SELECT ON models TO PUBLIC;
+"#,
+    name = "models"
+);
+
+/// Register a model under a name, or replace the model registered under it.
+///
+/// `params` are the sampling parameters generating with the model, e.g.
+/// `'{"temperature": 0.2, "max_tokens": 512}'`, parameters left out keep their defaults. The
+/// model is loaded on its first request, not when it is registered.
+///
+/// The file is refused unless it is a GGUF model and, if `sha256` is given, has that hash.
+#[pg_extern]
+fn register_model(
+    name: &str,
+    path: &str,
+    context_size: default!(i32, 4096),
+    chat_format: default!(&str, "'auto'"),
+    params: default!(pgrx::JsonB, "'{}'"),
+    sha256: default!(Option<&str>, "NULL"),
+) {
+    if context_size <= 0 {
+        error!("context_size must be positive, got {context_size}");
+    }
+
+    if let Err(e) = chat_format.parse::<ChatFormat>() {
+        error!("{e}");
+    }
+
+    if let Err(e) = serde_json::from_value::<Sampling>(params.0.clone()) {
+        error!("invalid params: {e}");
+    }
+
+    let actual = checksum(Path::new(path)).unwrap_or_else(|e| error::raise(&e));
+
+    if let Some(expected) = sha256.filter(|expected| !expected.eq_ignore_ascii_case(&actual)) {
+        error::invalid(
+            PgSqlErrorCode::ERRCODE_DATA_CORRUPTED,
+            format!("sha256 of {path} is {actual}, expected {expected}"),
+        );
+    }
+
+    Spi::run_with_args(
+        "INSERT INTO natural.models (name, path, context_size, chat_format, params, sha256)
+         VALUES ($1, $2, $3, $4, $5, $6)
+         ON CONFLICT (name) DO UPDATE
+         SET path = excluded.path,
+             context_size = excluded.context_size,
+             chat_format = excluded.chat_format,
+             params = excluded.params,
+             sha256 = excluded.sha256",
+        &[
+            name.into(),
+            path.into(),
+            context_size.into(),
+            chat_format.into(),
+            params.into(),
+            actual.into(),
+        ],
+    )
+    .unwrap_or_else(|e| error!("failed to register model {name:?}: {e}"));
+}
+
+// Models are read from arbitrary paths by the server
+extension_sql!(
+    r#"
+REVOKE ALL ON FUNCTION register_model FROM PUBLIC;
+REVOKE ALL ON FUNCTION install_model FROM PUBLIC;
+"#,
+    name = "register_model_grants",
+    requires = [register_model, install_model]
+);
+
+/// Install `file` of the Hugging Face repository `repo` and register it under `name`, by
+/// default the file name without extension. Returns the path the model was installed at.
+///
+/// The model is looked up in `natural.model_sources` without network access, and hard linked
+/// or copied below `$PGDATA/natural/models`. Files of a hub cache are checked against the
+/// sha256 the cache records for them, others against `sha256` if given.
+#[pg_extern]
+fn install_model(
+    repo: &str,
+    file: &str,
+    name: default!(Option<&str>, "NULL"),
+    sha256: default!(Option<&str>, "NULL"),
+) -> String {
+    let (path, expected) = install(repo, file).unwrap_or_else(|e| error::raise(&e));
+    let path = path.to_string_lossy();
+
+    register_model(
+        &name.map_or_else(|| hub::model_name(file), str::to_string),
+        &path,
+        4096,
+        "auto",
+        pgrx::JsonB(serde_json::json!({})),
+        sha256.or(expected.as_deref()),
+    );
+
+    path.into_owned()
+}
+
+/// Install a model, returning its path and sha256 if the source records it
+fn install(repo: &str, file: &str) -> Result<(PathBuf, Option<String>)> {
+    let sources = match guc::string(&guc::MODEL_SOURCES) {
+        Some(sources) => sources
+            .split(',')
+            .map(str::trim)
+            .filter(|source| !source.is_empty())
+            .map(PathBuf::from)
+            .collect(),
+        None => vec![hub::default_cache()],
+    };
+
+    let model = hub::resolve(repo, file, &sources)?;
+
+    // Backends run in the data directory, the path must hold for the daemon as well
+    let dir = std::env::current_dir()?.join(MODELS_DIR);
+
+    Ok((hub::install(&model, &dir, repo, file)?, model.sha256))
+}
+
+/// Metadata of a registered model, `natural.model` or the model of `natural.model_path`, read
+/// from the header of its file. Computing the sha256 reads all of the file.
+#[pg_extern]
+fn model_info(
+    name: default!(Option<&str>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(architecture, Option<String>),
+        name!(parameters, i64),
+        name!(quantization, Option<String>),
+        name!(context_length, Option<i64>),
+        name!(vocab_size, Option<i64>),
+        name!(chat_template, Option<String>),
+        name!(sha256, String),
+    ),
+> {
+    let path = model(name)
+        .and_then(|model| model.map_or_else(engine::default_model, Ok))
+        .map(|model| model.path)
+        .unwrap_or_else(|e| error::raise(&e));
+
+    let (info, sha256) = ModelInfo::read(&path)
+        .and_then(|info| Ok((info, gguf::sha256(&path)?)))
+        .wrap_err_with(|| format!("failed to read the model {}", path.display()))
+        .unwrap_or_else(|e| error::raise(&e));
+
+    TableIterator::once((
+        info.architecture,
+        info.parameters as i64,
+        info.quantization,
+        info.context_length.map(|n| n as i64),
+        info.vocab_size.map(|n| n as i64),
+        info.chat_template,
+        sha256,
+    ))
+}
+
+/// Resolve the model to generate with, either the one given or `natural.model`.
+///
+/// `None` if neither is set, the engine then uses the model of `natural.model_path`.
+pub fn model(name: Option<&str>) -> Result<Option<ModelSpec>> {
+    let Some(name) = name
+        .map(str::to_string)
+        .or_else(|| guc::string(&guc::MODEL))
+    else {
+        return Ok(None);
+    };
+
+    let row = Spi::connect(|client| {
+        let mut rows = client.select(
+            "SELECT path, context_size, chat_format, params, sha256
+             FROM natural.models WHERE name = $1",
+            Some(1),
+            &[name.as_str().into()],
+        )?;
+
+        rows.next()
+            .map(|row| -> Result<_> {
+                Ok((
+                    row.get_by_name::<String, _>("path")?,
+                    row.get_by_name::<i32, _>("context_size")?,
+                    row.get_by_name::<String, _>("chat_format")?,
+                    row.get_by_name::<pgrx::JsonB, _>("params")?,
+                    row.get_by_name::<String, _>("sha256")?,
+                ))
+            })
+            .transpose()
+    })?;
+
+    let Some((Some(path), Some(context_size), Some(chat_format), Some(params), sha256)) = row
+    else {
+        return Err(eyre!("Model {name:?} is not registered"));
+    };
+
+    Ok(Some(ModelSpec {
+        context_size: context_size as u32,
+        chat_format: chat_format.parse()?,
+        sampling: serde_json::from_value(params.0)?,
+        sha256,
+        ..ModelSpec::new(name, path)
+    }))
+}
+
+/// Sha256 of a GGUF model file, failing for other files
+fn checksum(path: &Path) -> Result<String> {
+    ModelInfo::read(path)
+        .and_then(|_| gguf::sha256(path))
+        .wrap_err_with(|| format!("failed to read the model {}", path.display()))
+}
@@ -0,0 +1,78 @@
+//! Access to tables roles must not write themselves.
+//!
+//! The cache and the history are shared by all roles, anyone able to write them could choose
+//! the sql others are given or cover their tracks. Roles have no access to them, they are read
+//! and written as the owner of the extension instead, like a `SECURITY DEFINER` function would.
+
+use eyre::{eyre, Result};
+use pgrx::prelude::*;
+
+/// Whether the transaction may write, it may not in read-only transactions and on standbys
+pub fn writable() -> bool {
+    unsafe { !pg_sys::XactReadOnly && !pg_sys::RecoveryInProgress() }
+}
+
+/// Run `f` as the owner of the extension, with a search path other roles cannot place
+/// objects in
+pub fn as_owner<R>(f: impl FnOnce() -> Result<R>) -> Result<R> {
+    let owner = Spi::get_one::<pg_sys::Oid>(
+        "SELECT extowner FROM pg_catalog.pg_extension WHERE extname = 'natural'",
+    )?
+    .ok_or_else(|| eyre!("Extension natural is not installed"))?;
+
+    let _owner = Owner::switch(owner);
+
+    f()
+}
+
+/// Restores the user and settings of the caller once dropped, also while unwinding from an
+/// error
+struct Owner {
+    user: pg_sys::Oid,
+    security_context: i32,
+    nest_level: i32,
+}
+
+impl Owner {
+    fn switch(owner: pg_sys::Oid) -> Self {
+        let mut user = pg_sys::InvalidOid;
+        let mut security_context = 0;
+
+        unsafe {
+            pg_sys::GetUserIdAndSecContext(&mut user, &mut security_context);
+            pg_sys::SetUserIdAndSecContext(
+                owner,
+                security_context
+                    | pg_sys::SECURITY_LOCAL_USERID_CHANGE as i32
+                    | pg_sys::SECURITY_RESTRICTED_OPERATION as i32,
+            );
+
+            let nest_level = pg_sys::NewGUCNestLevel();
+            pg_sys::set_config_option(
+                c"search_path".as_ptr(),
+                c"pg_catalog, pg_temp".as_ptr(),
+                pg_sys::GucContext::PGC_USERSET,
+                pg_sys::GucSource::PGC_S_SESSION,
+                pg_sys::GucAction::GUC_ACTION_SAVE,
+                true,
+                0,
+                false,
+            );
+
+            Self {
+                user,
+                security_context,
+                nest_level,
+            }
+        }
+    }
+}
+
+impl Drop for Owner {
+    fn drop(&mut self) {
+        unsafe {
+            pg_sys::AtEOXact_GUC(true, self.nest_level);
+            pg_sys::SetUserIdAndSecContext(self.user, self.security_context);
+        }
+    }
+}
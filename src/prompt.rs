@@ -0,0 +1,121 @@
+use eyre::{eyre, Result};
+use natural_driver::prompt::{Example, PromptTemplate};
+use pgrx::prelude::*;
+
+use crate::guc;
+
+extension_sql!(
+    r#"
+CREATE TABLE prompt_templates (
+    name text PRIMARY KEY,
+    system_prompt text NOT NULL,
+    user_prompt text NOT NULL,
+    -- NULL uses the stop sequences of the built-in template
+    stop_sequences text[]
+);
+
+CREATE TABLE prompt_examples (
+    id serial PRIMARY KEY,
+    template text NOT NULL REFERENCES prompt_templates (name) ON DELETE CASCADE,
+    question text NOT NULL,
+    sql text NOT NULL
+);
+
+-- Read by natural.query, changed by the functions below
+GRANT SELECT ON prompt_templates, prompt_examples TO PUBLIC;
+"#,
+    name = "prompt_templates"
+);
+
+/// Create or replace a prompt template after checking its syntax.
+///
+/// Generation ends once the output contains one of `stop_sequences`, by default the closing
+/// `</sql>` tag or the end of a statement.
+#[pg_extern]
+fn set_template(
+    name: &str,
+    system_prompt: &str,
+    user_prompt: &str,
+    stop_sequences: default!(Option<Vec<String>>, "NULL"),
+) {
+    if let Err(e) = PromptTemplate::new(name, system_prompt, user_prompt) {
+        error!("{e}");
+    }
+
+    Spi::run_with_args(
+        "INSERT INTO natural.prompt_templates (name, system_prompt, user_prompt, stop_sequences)
+         VALUES ($1, $2, $3, $4)
+         ON CONFLICT (name) DO UPDATE
+         SET system_prompt = excluded.system_prompt,
+             user_prompt = excluded.user_prompt,
+             stop_sequences = excluded.stop_sequences",
+        &[
+            name.into(),
+            system_prompt.into(),
+            user_prompt.into(),
+            stop_sequences.into(),
+        ],
+    )
+    .unwrap_or_else(|e| error!("failed to store template {name:?}: {e}"));
+}
+
+/// Resolve the template to use, either the one given or `natural.prompt_template`.
+///
+/// The built-in template is used for `default` unless it has been overridden in the table.
+pub fn template(name: Option<&str>) -> Result<PromptTemplate> {
+    let name = name
+        .map(str::to_string)
+        .or_else(|| guc::string(&guc::PROMPT_TEMPLATE))
+        .unwrap_or_else(|| "default".to_string());
+
+    let row = Spi::connect(|client| {
+        let mut rows = client.select(
+            "SELECT system_prompt, user_prompt, stop_sequences
+             FROM natural.prompt_templates WHERE name = $1",
+            Some(1),
+            &[name.as_str().into()],
+        )?;
+
+        rows.next()
+            .map(|row| -> Result<_> {
+                Ok((
+                    row.get_by_name::<String, _>("system_prompt")?,
+                    row.get_by_name::<String, _>("user_prompt")?,
+                    row.get_by_name::<Vec<String>, _>("stop_sequences")?,
+                ))
+            })
+            .transpose()
+    })?;
+
+    match row {
+        Some((Some(system), Some(user), stop)) => {
+            let template = PromptTemplate::new(name, &system, &user)?;
+
+            Ok(match stop {
+                Some(stop) => template.with_stop_sequences(stop),
+                None => template,
+            })
+        }
+        None if name == "default" => Ok(PromptTemplate::default()),
+        _ => Err(eyre!("Prompt template {name:?} does not exist")),
+    }
+}
+
+/// Few-shot examples stored for a template
+pub fn examples(template: &str) -> Result<Vec<Example>> {
+    Spi::connect(|client| {
+        client
+            .select(
+                "SELECT question, sql FROM natural.prompt_examples WHERE template = $1 ORDER BY id",
+                None,
+                &[template.into()],
+            )?
+            .map(|row| {
+                Ok(Example {
+                    question: row.get_by_name("question")?.unwrap_or_default(),
+                    sql: row.get_by_name("sql")?.unwrap_or_default(),
+                })
+            })
+            .collect()
+    })
+}
@@ -0,0 +1,34 @@
+//! Rewrites generated sql before it is checked and returned, see [`natural_driver::rewrite`].
+
+use eyre::Result;
+use natural_driver::rewrite::{self, Rewriter};
+use pgrx::prelude::*;
+use sqlparser::dialect::PostgreSqlDialect;
+
+use crate::guc;
+
+/// Bound sql generated for `question` by `natural.default_limit`, unless the question asks for
+/// all rows, qualify its tables with the schema they resolve to on the `search_path` and
+/// normalize the quoting of identifiers
+pub fn apply(question: &str, sql: &str) -> Result<String> {
+    let rewriter = Rewriter::new().with_schema_resolver(schema_of);
+
+    let mut rewriter = match guc::DEFAULT_LIMIT.get() {
+        0 => rewriter,
+        _ if rewrite::asks_for_all_rows(question) => rewriter,
+        rows => rewriter.with_limit(rows as u64),
+    };
+
+    Ok(rewriter.rewrite(sql, &PostgreSqlDialect {})?)
+}
+
+fn schema_of(table: &str) -> Option<String> {
+    Spi::get_one_with_args::<String>(
+        "SELECT quote_ident(n.nspname) FROM pg_class c
+         JOIN pg_namespace n ON n.oid = c.relnamespace
+         WHERE c.oid = to_regclass($1)",
+        &[table.into()],
+    )
+    .ok()
+    .flatten()
+}
@@ -0,0 +1,152 @@
+use eyre::{bail, Result};
+use natural_driver::generator::Outcome;
+use natural_driver::prompt::Turn;
+use pgrx::prelude::*;
+
+use crate::guc;
+
+/// Number of previous turns included in the prompt of a follow-up question
+const MAX_TURNS: i64 = 8;
+
+extension_sql!(
+    r#"
+CREATE TABLE sessions (
+    id uuid PRIMARY KEY,
+    owner name NOT NULL DEFAULT current_user,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    expires_at timestamptz NOT NULL
+);
+
+CREATE TABLE session_turns (
+    id bigserial PRIMARY KEY,
+    session uuid NOT NULL REFERENCES sessions (id) ON DELETE CASCADE,
+    question text NOT NULL,
+    sql text,
+    clarification text,
+    summary text,
+    created_at timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE INDEX ON session_turns (session, id);
+
+-- Every role keeps sessions, but only sees its own
+ALTER TABLE sessions ENABLE ROW LEVEL SECURITY;
+CREATE POLICY owner ON sessions USING (owner = current_user);
+
+ALTER TABLE session_turns ENABLE ROW LEVEL SECURITY;
+CREATE POLICY owner ON session_turns USING (session IN (SELECT id FROM sessions));
+
+GRANT SELECT, INSERT, UPDATE, DELETE ON sessions, session_turns TO PUBLIC;
+GRANT USAGE ON SEQUENCE session_turns_id_seq TO PUBLIC;
+"#,
+    name = "sessions"
+);
+
+/// Start a conversation, follow-up questions passed with the returned id see the previous ones
+#[pg_extern]
+fn session_start() -> pgrx::Uuid {
+    let id = pgrx::Uuid::from_bytes(*uuid::Uuid::new_v4().as_bytes());
+
+    // Only expired sessions of the caller, the others are hidden by row level security
+    Spi::run("DELETE FROM natural.sessions WHERE expires_at < now()")
+        .and_then(|_| {
+            Spi::run_with_args(
+                "INSERT INTO natural.sessions (id, expires_at)
+                 VALUES ($1, now() + make_interval(secs => $2))",
+                &[id.into(), f64::from(guc::SESSION_TIMEOUT.get()).into()],
+            )
+        })
+        .unwrap_or_else(|e| error!("failed to start session: {e}"));
+
+    id
+}
+
+/// End a conversation and drop its history
+#[pg_extern]
+fn session_end(session: pgrx::Uuid) -> bool {
+    Spi::get_one_with_args::<bool>(
+        "WITH deleted AS (
+            DELETE FROM natural.sessions WHERE id = $1 AND owner = current_user RETURNING 1
+         )
+         SELECT count(*) > 0 FROM deleted",
+        &[session.into()],
+    )
+    .unwrap_or_else(|e| error!("failed to end session: {e}"))
+    .unwrap_or(false)
+}
+
+/// Attach a short description of the result to the last question of a session
+#[pg_extern]
+fn session_note(session: pgrx::Uuid, summary: &str) {
+    Spi::run_with_args(
+        "UPDATE natural.session_turns SET summary = $2
+         WHERE id = (
+            SELECT t.id FROM natural.session_turns t
+            JOIN natural.sessions s ON s.id = t.session
+            WHERE t.session = $1 AND s.owner = current_user
+            ORDER BY t.id DESC LIMIT 1
+         )",
+        &[session.into(), summary.into()],
+    )
+    .unwrap_or_else(|e| error!("failed to annotate session: {e}"));
+}
+
+/// Previous turns of a session, oldest first. Using a session extends its expiry.
+pub fn history(session: pgrx::Uuid) -> Result<Vec<Turn>> {
+    let alive = Spi::get_one_with_args::<bool>(
+        "WITH touched AS (
+            UPDATE natural.sessions SET expires_at = now() + make_interval(secs => $2)
+            WHERE id = $1 AND owner = current_user AND expires_at >= now()
+            RETURNING 1
+         )
+         SELECT count(*) > 0 FROM touched",
+        &[session.into(), f64::from(guc::SESSION_TIMEOUT.get()).into()],
+    )?;
+
+    if alive != Some(true) {
+        bail!("Session {session} does not exist or has expired");
+    }
+
+    Spi::connect(|client| {
+        client
+            .select(
+                "SELECT question, sql, clarification, summary FROM (
+                    SELECT * FROM natural.session_turns WHERE session = $1
+                    ORDER BY id DESC LIMIT $2
+                 ) t ORDER BY id",
+                None,
+                &[session.into(), MAX_TURNS.into()],
+            )?
+            .map(|row| {
+                Ok(Turn {
+                    question: row.get_by_name("question")?.unwrap_or_default(),
+                    sql: row.get_by_name("sql")?.unwrap_or_default(),
+                    clarification: row.get_by_name("clarification")?,
+                    summary: row.get_by_name("summary")?,
+                })
+            })
+            .collect()
+    })
+}
+
+/// Append a question and the sql or clarifying question generated for it to a session. The
+/// next question of the session is taken as the answer to a clarifying question.
+pub fn record(session: pgrx::Uuid, question: &str, outcome: &Outcome) -> Result<()> {
+    let clarification = match outcome {
+        Outcome::Clarification(clarification) => Some(clarification.to_string()),
+        Outcome::Sql { .. } | Outcome::Explanation(_) => None,
+    };
+
+    Spi::run_with_args(
+        "INSERT INTO natural.session_turns (session, question, sql, clarification)
+         VALUES ($1, $2, $3, $4)",
+        &[
+            session.into(),
+            question.into(),
+            outcome.sql().into(),
+            clarification.into(),
+        ],
+    )?;
+
+    Ok(())
+}
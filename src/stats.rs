@@ -0,0 +1,414 @@
+//! Statistics about generated sql, kept in shared memory.
+//!
+//! Counters are accumulated per database and role and exposed by the `natural.stats` view,
+//! questions currently being answered by `natural.stat_activity`. Both require natural to be
+//! loaded through `shared_preload_libraries`, otherwise nothing is collected.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use pgrx::prelude::*;
+use pgrx::{pg_shmem_init, PGRXSharedMemory, PgLwLock};
+
+use crate::history::{now, Entry, Status};
+
+/// Number of distinct database and role combinations tracked
+const MAX_ENTRIES: usize = 256;
+
+/// Number of questions tracked in `natural.stat_activity` at once
+const MAX_ACTIVITY: usize = 256;
+
+/// Bytes of a question kept in `natural.stat_activity`
+const QUESTION_LEN: usize = 256;
+
+static STATS: PgLwLock<Stats> = unsafe { PgLwLock::new(c"natural_stats") };
+static ACTIVITY: PgLwLock<Activity> = unsafe { PgLwLock::new(c"natural_activity") };
+
+/// Whether the shared memory was reserved, inherited by the backends of the postmaster
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether this backend warned about statistics not being collected
+static WARNED: AtomicBool = AtomicBool::new(false);
+
+extension_sql!(
+    r#"
+CREATE VIEW stats AS
+SELECT
+    d.datname AS database,
+    r.rolname AS role,
+    s.calls,
+    s.cache_hits,
+    s.cache_hits::float8 / nullif(s.calls, 0) AS cache_hit_ratio,
+    s.failures,
+    s.prompt_tokens,
+    s.cached_tokens,
+    s.generated_tokens,
+    s.generated_tokens / nullif(s.generation_time, 0) * 1000 AS tokens_per_second,
+    s.total_time,
+    s.total_time / nullif(s.calls, 0) AS mean_time,
+    s.max_time,
+    s.queue_time,
+    s.prompt_time,
+    s.generation_time,
+    to_timestamp(s.stats_reset) AS stats_reset
+FROM natural.stats_entries() s
+LEFT JOIN pg_database d ON d.oid = s.dbid
+LEFT JOIN pg_roles r ON r.oid = s.userid;
+
+CREATE VIEW stat_activity AS
+SELECT
+    a.pid,
+    d.datname AS database,
+    r.rolname AS role,
+    a.question,
+    to_timestamp(a.query_start) AS query_start,
+    now() - to_timestamp(a.query_start) AS duration
+FROM natural.stat_activity_entries() a
+LEFT JOIN pg_database d ON d.oid = a.dbid
+LEFT JOIN pg_roles r ON r.oid = a.userid;
+
+-- Questions of other roles are not for everyone to read
+REVOKE ALL ON stat_activity FROM PUBLIC;
+REVOKE ALL ON FUNCTION stat_activity_entries() FROM PUBLIC;
+GRANT SELECT ON stat_activity TO pg_read_all_stats;
+
+REVOKE ALL ON FUNCTION stats_reset() FROM PUBLIC;
+"#,
+    name = "stats",
+    requires = [stats_entries, stat_activity_entries, stats_reset]
+);
+
+#[derive(Clone, Copy)]
+struct Counters {
+    dbid: pg_sys::Oid,
+    userid: pg_sys::Oid,
+    calls: i64,
+    cache_hits: i64,
+    failures: i64,
+    prompt_tokens: i64,
+    cached_tokens: i64,
+    generated_tokens: i64,
+    total_time: f64,
+    max_time: f64,
+    queue_time: f64,
+    prompt_time: f64,
+    generation_time: f64,
+}
+
+impl Counters {
+    const EMPTY: Self = Self {
+        dbid: pg_sys::InvalidOid,
+        userid: pg_sys::InvalidOid,
+        calls: 0,
+        cache_hits: 0,
+        failures: 0,
+        prompt_tokens: 0,
+        cached_tokens: 0,
+        generated_tokens: 0,
+        total_time: 0.0,
+        max_time: 0.0,
+        queue_time: 0.0,
+        prompt_time: 0.0,
+        generation_time: 0.0,
+    };
+}
+
+#[derive(Clone, Copy)]
+pub struct Stats {
+    entries: [Counters; MAX_ENTRIES],
+    /// Seconds since the unix epoch, 0 until the first reset
+    reset: f64,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            entries: [Counters::EMPTY; MAX_ENTRIES],
+            reset: 0.0,
+        }
+    }
+}
+
+unsafe impl PGRXSharedMemory for Stats {}
+
+#[derive(Clone, Copy)]
+struct Running {
+    /// Backend answering the question, 0 marks a free slot
+    pid: i32,
+    dbid: pg_sys::Oid,
+    userid: pg_sys::Oid,
+    started: f64,
+    question: [u8; QUESTION_LEN],
+    question_len: usize,
+}
+
+impl Running {
+    const EMPTY: Self = Self {
+        pid: 0,
+        dbid: pg_sys::InvalidOid,
+        userid: pg_sys::InvalidOid,
+        started: 0.0,
+        question: [0; QUESTION_LEN],
+        question_len: 0,
+    };
+}
+
+#[derive(Clone, Copy)]
+pub struct Activity {
+    running: [Running; MAX_ACTIVITY],
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Self {
+            running: [Running::EMPTY; MAX_ACTIVITY],
+        }
+    }
+}
+
+unsafe impl PGRXSharedMemory for Activity {}
+
+/// Reserve the shared memory, must be called from `_PG_init`. Only possible while the
+/// postmaster loads natural, not when a backend loads it on first use.
+pub fn init() {
+    if unsafe { !pg_sys::process_shared_preload_libraries_in_progress } {
+        return;
+    }
+
+    pg_shmem_init!(STATS);
+    pg_shmem_init!(ACTIVITY);
+
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Whether statistics are collected, warning once per backend if not
+fn enabled() -> bool {
+    let enabled = ENABLED.load(Ordering::Relaxed);
+
+    if !enabled && !WARNED.swap(true, Ordering::Relaxed) {
+        warning!("natural is not in shared_preload_libraries, statistics are not collected");
+    }
+
+    enabled
+}
+
+/// Fail reading statistics that are not collected
+fn require() {
+    if !ENABLED.load(Ordering::Relaxed) {
+        error!("natural statistics require natural in shared_preload_libraries");
+    }
+}
+
+/// Account a finished call of `natural.query`
+pub fn record(entry: &Entry) {
+    if !enabled() {
+        return;
+    }
+
+    let (dbid, userid) = current();
+
+    let mut stats = STATS.exclusive();
+
+    let Some(counters) = stats
+        .entries
+        .iter_mut()
+        .find(|c| (c.dbid == dbid && c.userid == userid) || c.dbid == pg_sys::InvalidOid)
+    else {
+        // Out of entries, like pg_stat_statements without eviction
+        return;
+    };
+
+    counters.dbid = dbid;
+    counters.userid = userid;
+    counters.calls += 1;
+    counters.total_time += entry.latency_ms;
+    counters.max_time = counters.max_time.max(entry.latency_ms);
+
+    match entry.status {
+        Status::Cached => counters.cache_hits += 1,
+        Status::Failed => counters.failures += 1,
+        Status::Generated | Status::Clarification => {}
+    }
+
+    counters.prompt_tokens += entry.tokens_in;
+    counters.cached_tokens += entry.cached_tokens;
+    counters.generated_tokens += entry.tokens_out;
+
+    if let Some(timings) = entry.timings {
+        counters.queue_time += timings.queue_ms;
+        counters.prompt_time += timings.prompt_ms;
+        counters.generation_time += timings.generation_ms;
+    }
+}
+
+/// Guard listing the question of this backend in `natural.stat_activity` while it lives
+pub struct Tracked {
+    pid: i32,
+}
+
+impl Tracked {
+    pub fn start(question: &str) -> Self {
+        let pid = unsafe { pg_sys::MyProcPid };
+
+        if !enabled() {
+            return Self { pid };
+        }
+
+        let (dbid, userid) = current();
+
+        let mut activity = ACTIVITY.exclusive();
+
+        // A backend answers one question at a time, reuse its slot if a previous call leaked it
+        let slot = activity
+            .running
+            .iter()
+            .position(|r| r.pid == pid)
+            .or_else(|| activity.running.iter().position(|r| r.pid == 0));
+
+        if let Some(slot) = slot {
+            let mut running = Running {
+                pid,
+                dbid,
+                userid,
+                started: now(),
+                ..Running::EMPTY
+            };
+
+            let len = floor_char_boundary(question, QUESTION_LEN);
+            running.question[..len].copy_from_slice(&question.as_bytes()[..len]);
+            running.question_len = len;
+
+            activity.running[slot] = running;
+        }
+
+        Self { pid }
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let mut activity = ACTIVITY.exclusive();
+
+        for running in activity.running.iter_mut().filter(|r| r.pid == self.pid) {
+            *running = Running::EMPTY;
+        }
+    }
+}
+
+/// Counters per database and role. Backs the `natural.stats` view.
+#[pg_extern]
+#[allow(clippy::type_complexity)]
+fn stats_entries() -> TableIterator<
+    'static,
+    (
+        name!(dbid, pg_sys::Oid),
+        name!(userid, pg_sys::Oid),
+        name!(calls, i64),
+        name!(cache_hits, i64),
+        name!(failures, i64),
+        name!(prompt_tokens, i64),
+        name!(cached_tokens, i64),
+        name!(generated_tokens, i64),
+        name!(total_time, f64),
+        name!(max_time, f64),
+        name!(queue_time, f64),
+        name!(prompt_time, f64),
+        name!(generation_time, f64),
+        name!(stats_reset, Option<f64>),
+    ),
+> {
+    require();
+
+    let stats = *STATS.share();
+    let reset = Some(stats.reset).filter(|reset| *reset > 0.0);
+
+    TableIterator::new(
+        stats
+            .entries
+            .into_iter()
+            .filter(|c| c.dbid != pg_sys::InvalidOid)
+            .map(move |c| {
+                (
+                    c.dbid,
+                    c.userid,
+                    c.calls,
+                    c.cache_hits,
+                    c.failures,
+                    c.prompt_tokens,
+                    c.cached_tokens,
+                    c.generated_tokens,
+                    c.total_time,
+                    c.max_time,
+                    c.queue_time,
+                    c.prompt_time,
+                    c.generation_time,
+                    reset,
+                )
+            }),
+    )
+}
+
+/// Questions currently being answered. Backs the `natural.stat_activity` view.
+#[pg_extern]
+fn stat_activity_entries() -> TableIterator<
+    'static,
+    (
+        name!(pid, i32),
+        name!(dbid, pg_sys::Oid),
+        name!(userid, pg_sys::Oid),
+        name!(question, String),
+        name!(query_start, f64),
+    ),
+> {
+    require();
+
+    let activity = *ACTIVITY.share();
+
+    TableIterator::new(
+        activity
+            .running
+            .into_iter()
+            .filter(|r| r.pid != 0)
+            .map(|r| {
+                (
+                    r.pid,
+                    r.dbid,
+                    r.userid,
+                    String::from_utf8_lossy(&r.question[..r.question_len]).into_owned(),
+                    r.started,
+                )
+            }),
+    )
+}
+
+/// Discard all statistics gathered so far
+#[pg_extern]
+fn stats_reset() {
+    require();
+
+    let mut stats = STATS.exclusive();
+
+    *stats = Stats {
+        reset: now(),
+        ..Stats::default()
+    };
+}
+
+fn current() -> (pg_sys::Oid, pg_sys::Oid) {
+    unsafe { (pg_sys::MyDatabaseId, pg_sys::GetUserId()) }
+}
+
+/// Longest prefix of at most `len` bytes not splitting a character
+fn floor_char_boundary(s: &str, len: usize) -> usize {
+    if s.len() <= len {
+        return s.len();
+    }
+
+    (0..=len)
+        .rev()
+        .find(|i| s.is_char_boundary(*i))
+        .unwrap_or(0)
+}
@@ -0,0 +1,46 @@
+use eyre::Result;
+use natural_driver::server::Server;
+use pgrx::bgworkers::*;
+use pgrx::prelude::*;
+
+use crate::{engine, guc, logging};
+
+/// Inference worker hosting the models for all backends.
+///
+/// Requests arrive over [`engine::WORKER_SOCKET`] and are served concurrently, up to
+/// `natural.parallel` at a time per model. The default model is loaded at start, registered
+/// models on their first request. Between requests the worker rereads the configuration on
+/// SIGHUP.
+#[pg_guard]
+#[no_mangle]
+pub extern "C-unwind" fn natural_inference_worker(_arg: pg_sys::Datum) {
+    BackgroundWorker::attach_signal_handlers(SignalWakeFlags::SIGHUP | SignalWakeFlags::SIGTERM);
+
+    log!("{} started", BackgroundWorker::get_name());
+
+    if let Err(e) = run() {
+        log!("{} failed: {e:#}", BackgroundWorker::get_name());
+    }
+}
+
+fn run() -> Result<()> {
+    let mut pool = engine::pool(guc::PARALLEL.get() as usize)?;
+    pool.preload()?;
+
+    Server::bind(engine::WORKER_SOCKET)?.serve(&mut pool, || {
+        if BackgroundWorker::sighup_received() {
+            reload();
+        }
+
+        // Events of the connection threads
+        logging::flush();
+
+        !BackgroundWorker::sigterm_received()
+    })
+}
+
+/// Reread the configuration so that a changed `natural.log_level` applies
+fn reload() {
+    unsafe { pg_sys::ProcessConfigFile(pg_sys::GucContext::PGC_SIGHUP) };
+    logging::refresh();
+}